@@ -0,0 +1,366 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::error::*;
+use crate::eval::{CONSTANTS, DIMENSIONLESS_FNS, UNIT_PRESERVING_FNS};
+use crate::types::{self, *};
+
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+
+// the units of the variables in scope, values are never looked at. The
+// conversions decide which targets a ':' can reach
+#[derive(Debug, Clone, Default)]
+pub struct TypeEnv<'a> {
+    pub vars: HashMap<&'a str, Unit<'a>>,
+    pub conversions: ConversionTable<'a>,
+}
+
+impl<'a> TypeEnv<'a> {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn bind(&mut self, name: &'a str, unit: Unit<'a>) {
+        self.vars.insert(name, unit);
+    }
+}
+
+// a dry run that only follows units through the tree. Every mismatch is
+// collected, a subexpression whose unit can't be known without its value,
+// like `x ^ y`, is skipped instead of reported
+#[allow(dead_code)]
+pub fn check_units<'a>(
+    node: &Node<'a>,
+    env: &TypeEnv<'a>,
+) -> Result<Unit<'a>, Vec<MorphError<'a>>> {
+    let mut checker = Checker {
+        env: env.clone(),
+        errors: vec![],
+    };
+
+    let unit = checker.unit(node);
+    match checker.errors.is_empty() {
+        true => Ok(unit.unwrap_or_default()),
+        false => Err(checker.errors),
+    }
+}
+
+struct Checker<'a> {
+    env: TypeEnv<'a>,
+    errors: Vec<MorphError<'a>>,
+}
+
+impl<'a> Checker<'a> {
+    fn mismatch(&mut self, span: Range<usize>, op: &str, lhs: &Unit<'a>, rhs: &Unit<'a>) {
+        self.errors.push(MorphError::custom(
+            span,
+            format!(
+                "non-conformable units for '{}': ({} {} {})",
+                op, lhs, op, rhs
+            ),
+            ErrorType::TypeError,
+        ));
+    }
+
+    fn dimensionless(&mut self, n: &Node<'a>, unit: &Option<Unit<'a>>, what: &str) {
+        if let Some(unit) = unit.as_ref().filter(|u| u.has_units()) {
+            self.errors.push(MorphError::custom(
+                n.span.clone(),
+                format!("{} must be dimensionless, found: {}", what, unit),
+                ErrorType::TypeError,
+            ));
+        }
+    }
+
+    // the same test convert_to makes, both units have to reach the same base
+    fn convertible(&mut self, n: &Node<'a>, from: &Unit<'a>, to: &Unit<'a>) {
+        let mut table = self.env.conversions.clone();
+        table.register_prefixes(from);
+        table.register_prefixes(to);
+
+        if table.to_base(from).0 != table.to_base(to).0 {
+            self.errors.push(MorphError::custom(
+                n.span.clone(),
+                format!("can't convert {} to {}", from, to),
+                ErrorType::TypeError,
+            ));
+        }
+    }
+
+    // both sides need the same dimension, the result has the unit of the lhs
+    fn conformable(&mut self, lhs: &Node<'a>, rhs: &Node<'a>, op: &str) -> Option<Unit<'a>> {
+        let (l, r) = (self.unit(lhs), self.unit(rhs));
+
+        match (l, r) {
+            (Some(l), Some(r)) if !l.same_dimension(&r) => {
+                // the operator sits between its operands
+                let span = match lhs.span.end <= rhs.span.start {
+                    true => lhs.span.end..rhs.span.start,
+                    false => merge_span(&lhs.span, &rhs.span),
+                };
+                self.mismatch(span, op, &l, &r);
+                None
+            }
+            (l, r) => l.or(r),
+        }
+    }
+
+    fn compare(&mut self, lhs: &Node<'a>, rhs: &Node<'a>, op: &str) -> Option<Unit<'a>> {
+        self.conformable(lhs, rhs, op).map(|_| Unit::none())
+    }
+
+    fn combine(
+        &mut self,
+        lhs: &Node<'a>,
+        rhs: &Node<'a>,
+        op: fn(Unit<'a>, Unit<'a>) -> Unit<'a>,
+    ) -> Option<Unit<'a>> {
+        let (l, r) = (self.unit(lhs), self.unit(rhs));
+        Some(op(l?, r?))
+    }
+
+    fn integer(&mut self, lhs: &Node<'a>, rhs: &Node<'a>, op: &str) -> Option<Unit<'a>> {
+        let (l, r) = (self.unit(lhs), self.unit(rhs));
+        let what = format!("an operand of '{}'", op);
+        self.dimensionless(lhs, &l, &what);
+        self.dimensionless(rhs, &r, &what);
+        Some(Unit::none())
+    }
+
+    fn assign(&mut self, name: &'a str, val: &Node<'a>) -> Option<Unit<'a>> {
+        let unit = self.unit(val);
+        match &unit {
+            Some(unit) => self.env.bind(name, unit.clone()),
+            None => {
+                self.env.vars.remove(name);
+            }
+        }
+
+        unit
+    }
+
+    fn unit(&mut self, n: &Node<'a>) -> Option<Unit<'a>> {
+        use NodeType::*;
+
+        match &n.typ {
+            Num(_) => Some(types::Unit::none()),
+            Unit(name) => match self.env.vars.get(name) {
+                Some(unit) => Some(unit.clone()),
+                None if CONSTANTS.iter().any(|(c, _)| c == name) => Some(types::Unit::none()),
+                None => Some(types::Unit::from(UnitAtom::base(name))),
+            },
+            Def(name) => Some(types::Unit::from(UnitAtom::base(name))),
+            DefFn { .. } | ParseError => None,
+
+            Add(lhs, rhs) => self.conformable(lhs, rhs, "+"),
+            Sub(lhs, rhs) => self.conformable(lhs, rhs, "-"),
+            Mod(lhs, rhs) => self.conformable(lhs, rhs, "mod"),
+            Mul(lhs, rhs) => self.combine(lhs, rhs, |l, r| l * r),
+            Div(lhs, rhs) => self.combine(lhs, rhs, |l, r| l / r),
+            Pow(lhs, rhs) => {
+                let (base, exp) = (self.unit(lhs), self.unit(rhs));
+                self.dimensionless(rhs, &exp, "an exponent");
+
+                match (base?, literal(rhs)) {
+                    (base, _) if base.is_dimensionless() => Some(base),
+                    (base, Some(exp)) => Some(base.pow(exp)),
+                    _ => None,
+                }
+            }
+
+            Equal(lhs, rhs) => self.compare(lhs, rhs, "=="),
+            NeEqual(lhs, rhs) => self.compare(lhs, rhs, "!="),
+            GreaterEqual(lhs, rhs) => self.compare(lhs, rhs, ">="),
+            LesserEqual(lhs, rhs) => self.compare(lhs, rhs, "<="),
+            Greater(lhs, rhs) => self.compare(lhs, rhs, ">"),
+            Lesser(lhs, rhs) => self.compare(lhs, rhs, "<"),
+
+            BitAnd(lhs, rhs) => self.integer(lhs, rhs, "&"),
+            BitOr(lhs, rhs) => self.integer(lhs, rhs, "|"),
+            Shl(lhs, rhs) => self.integer(lhs, rhs, "<<"),
+            Shr(lhs, rhs) => self.integer(lhs, rhs, ">>"),
+
+            UnrySub(val) | Percent(val) | Abs(val) => self.unit(val),
+            UnryNot(val) => {
+                self.unit(val);
+                Some(types::Unit::none())
+            }
+
+            IfElse {
+                cond,
+                if_body,
+                else_body,
+            } => {
+                let unit = self.unit(cond);
+                self.dimensionless(cond, &unit, "condition");
+
+                let if_unit = self.unit(if_body);
+                match else_body {
+                    Some(body) => {
+                        let else_unit = self.unit(body);
+                        match (if_unit, else_unit) {
+                            (Some(l), Some(r)) if !l.same_dimension(&r) => {
+                                self.errors.push(MorphError::custom(
+                                    body.span.clone(),
+                                    format!("branches have different units: {} and {}", l, r),
+                                    ErrorType::TypeError,
+                                ));
+                                None
+                            }
+                            (l, r) => l.or(r),
+                        }
+                    }
+                    None => if_unit,
+                }
+            }
+            Convert { expr, target } => {
+                if let Some(unit) = self.unit(expr) {
+                    self.convertible(n, &unit, target);
+                }
+                Some(target.clone())
+            }
+
+            Assign(name, val) => self.assign(name, val),
+            AddAssign(name, val) | SubAssign(name, val) => {
+                let unit = self.unit(val);
+                match (self.env.vars.get(name).cloned(), unit) {
+                    (Some(l), Some(r)) if !l.same_dimension(&r) => {
+                        let op = match n.typ {
+                            AddAssign(..) => "+",
+                            _ => "-",
+                        };
+                        self.mismatch(val.span.clone(), op, &l, &r);
+                        None
+                    }
+                    (l, r) => l.or(r),
+                }
+            }
+            MulAssign(name, val) | DivAssign(name, val) | PowAssign(name, val) => {
+                self.unit(val);
+                // the new unit depends on the variable, which may be unknown
+                self.env.vars.remove(name);
+                None
+            }
+
+            FnCall { name, args } => {
+                let units: Vec<_> = args.iter().map(|a| self.unit(a)).collect();
+                match (*name, units.as_slice()) {
+                    ("sqrt", [Some(unit)]) => Some(unit.clone().pow(dec!(0.5))),
+                    (name, [Some(unit)]) if UNIT_PRESERVING_FNS.contains(&name) => {
+                        Some(unit.clone())
+                    }
+                    // an angle passed to a trig function is checked when the
+                    // value is known
+                    (name, _) if DIMENSIONLESS_FNS.contains(&name) => Some(types::Unit::none()),
+                    _ => None,
+                }
+            }
+            Scope(nodes) => {
+                let outer = self.env.clone();
+                let unit = nodes.iter().map(|n| self.unit(n)).last();
+                self.env = outer;
+                unit.unwrap_or_else(|| Some(types::Unit::none()))
+            }
+            List(items) => {
+                for item in items {
+                    self.unit(item);
+                }
+                None
+            }
+        }
+    }
+}
+
+// an exponent that is written out, possibly negated
+fn literal(n: &Node<'_>) -> Option<Decimal> {
+    match &n.typ {
+        NodeType::Num(x) => Some(*x),
+        NodeType::UnrySub(val) => literal(val).map(|x| -x),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::morph::test_utils::*;
+
+    fn check(src: &str) -> Result<Unit<'_>, Vec<MorphError<'_>>> {
+        check_units(&parse(src).0.unwrap(), &TypeEnv::new())
+    }
+
+    fn unit(src: &str) -> String {
+        check(src).unwrap().to_string()
+    }
+
+    #[test]
+    fn units() {
+        eq!(unit("def m; def s; 3 m / (2 s)^2"), "[m/s^2]");
+        eq!(unit("def m; x = 2 m; y = x * x; y"), "[m^2]");
+        eq!(unit("def m; sqrt(4 m^2)"), "[m]");
+        eq!(unit("def m; x = 1 m; x += 2 m; -x"), "[m]");
+        eq!(unit("def m; 1 m < 2 m"), "");
+        eq!(unit("def m; x = 1 m; { x = 2 }; x"), "[m]");
+        eq!(unit("def m; 2 m : km"), "[km]");
+        eq!(unit("x = 2; 3 ^ x"), "");
+        eq!(unit("def m; log(2 m / (1 m)) * 3"), "");
+        eq!(unit("def deg; x = sin(90 deg); x + 1"), "");
+    }
+
+    #[test]
+    fn mismatches() {
+        let src = "3m + 4s * 2";
+        let errs = check(src).unwrap_err();
+        eq!(errs.len(), 1);
+        eq!(errs[0].typ, ErrorType::TypeError);
+        eq!(src[errs[0].span.into_range()].trim(), "+");
+        eq!(
+            errs[0].to_string(),
+            "non-conformable units for '+': ([m] + [s])"
+        );
+
+        // values are never computed, so neither the division by zero nor the
+        // overflow are found, but every mismatch is
+        let errs =
+            check("x = 1 m / 0; x - 1 s; 99999999999999999999 ^ 99 * 1 s == 1 m").unwrap_err();
+        let msgs: Vec<_> = errs.iter().map(|e| e.to_string()).collect();
+        eq!(
+            msgs,
+            vec![
+                "non-conformable units for '-': ([m] - [s])",
+                "non-conformable units for '==': ([s] == [m])",
+            ]
+        );
+
+        let errs = check("x = 1 m; x += 2 s; if x { 1 m } else { 1 s }").unwrap_err();
+        let msgs: Vec<_> = errs.iter().map(|e| e.to_string()).collect();
+        eq!(
+            msgs,
+            vec![
+                "non-conformable units for '+': ([m] + [s])",
+                "condition must be dimensionless, found: [m]",
+                "branches have different units: [m] and [s]",
+            ]
+        );
+
+        eq!(check("2 m & 1").unwrap_err().len(), 1);
+
+        let src = "def m; def s; 2 m : s; 1 m : km";
+        let errs = check(src).unwrap_err();
+        eq!(errs.len(), 1);
+        eq!(&src[errs[0].span.into_range()], "2 m : s");
+        eq!(errs[0].to_string(), "can't convert [m] to [s]");
+    }
+
+    #[test]
+    fn conversions() {
+        let mut env = TypeEnv::new();
+        env.conversions.register("inch", "m", dec!(0.0254));
+
+        let node = parse("def m; 2 m : inch").0.unwrap();
+        eq!(check_units(&node, &env).unwrap().to_string(), "[inch]");
+        assert!(check("def m; 2 m : inch").is_err());
+        eq!(unit("x = 2; pi * e * x"), "");
+    }
+}
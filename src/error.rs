@@ -59,8 +59,9 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum ErrorType {
+    #[default]
     Other = 0,
     CouldNotLex = 1,
     UndefinedSyntax = 2,
@@ -68,6 +69,12 @@ pub enum ErrorType {
     UndefinedIdent = 4,
     TypeError = 5,
     ZeroDivision = 6,
+    DomainError = 7,
+    Overflow = 8,
+    TooDeep = 9,
+    ExpectedOperand = 10,
+    InvalidAssignTarget = 11,
+    UnknownUnit = 12,
 }
 
 impl ErrorType {
@@ -87,6 +94,12 @@ impl ErrorType {
             UndefinedIdent => "UndefinedIdent: encountered undefined identifier at runtime",
             TypeError => "UnsupportedType: type is not compatible",
             ZeroDivision => "ZeroDivision: encountered zero division at runtime",
+            DomainError => "DomainError: value is outside the domain of the operation",
+            Overflow => "Overflow: result does not fit into a number",
+            TooDeep => "TooDeep: input or call is nested too deeply",
+            ExpectedOperand => "ExpectedOperand: operator is missing its right operand",
+            InvalidAssignTarget => "InvalidAssignTarget: only identifiers can be assigned to",
+            UnknownUnit => "UnknownUnit: name is neither a declared unit nor bound",
             Other => "",
         }
     }
@@ -98,12 +111,6 @@ impl fmt::Display for ErrorType {
     }
 }
 
-impl Default for ErrorType {
-    fn default() -> Self {
-        Self::Other
-    }
-}
-
 #[derive(Debug, PartialEq)]
 pub struct MorphError<'a> {
     pub span: SimpleSpan<usize>,
@@ -144,6 +151,32 @@ impl<'a> MorphError<'a> {
             .unwrap();
     }
 
+    // plain text version of `report` for places without a terminal, the
+    // offending range is underlined on the line it starts on
+    pub fn snippet(&self, src: &str) -> String {
+        let start = self.span.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let line = src[line_start..line_end].trim_end_matches('\r');
+
+        let line_nr = src[..line_start].matches('\n').count() + 1;
+        let col = src[line_start..start].chars().count();
+        let end = self.span.end.clamp(start, line_start + line.len());
+        let width = src[start..end].chars().count().max(1);
+
+        let gutter = " ".repeat(line_nr.to_string().len());
+        format!(
+            "error[{:02}]: {}\n{} | {}\n{} | {}{}",
+            self.err_code(),
+            self,
+            line_nr,
+            line,
+            gutter,
+            " ".repeat(col),
+            "^".repeat(width),
+        )
+    }
+
     pub fn err_code(&self) -> u32 {
         self.typ.code()
     }
@@ -1,14 +1,40 @@
-use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc};
-
-use rust_decimal_macros::dec;
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, ops, ops::Range, rc::Rc};
 
 use crate::error::*;
 use crate::types::*;
+use crate::visit::{walk_node, Visitor};
+
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+
+// looked up after units and variables, so both can shadow them
+pub const CONSTANTS: [(&str, Decimal); 2] = [("pi", Decimal::PI), ("e", Decimal::E)];
+
+// the builtins grouped by the unit of their result, for passes that don't
+// compute values
+pub const UNIT_PRESERVING_FNS: [&str; 5] = ["abs", "floor", "ceil", "round", "trunc"];
+// user functions nested deeper than this would overflow a 2 MiB thread
+// stack, unoptimized builds take several times more stack per call
+pub const MAX_CALL_DEPTH: usize = if cfg!(debug_assertions) { 16 } else { 128 };
+pub const DIMENSIONLESS_FNS: [&str; 6] = ["ln", "log", "exp", "sin", "cos", "tan"];
 
 #[derive(Debug, Clone, Default)]
 pub struct ContextCore<'a> {
     pub base_units: HashMap<&'a str, Quantity<'a>>,
-    pub vars: HashMap<&'a str, Quantity<'a>>,
+    pub vars: HashMap<&'a str, Value<'a>>,
+    pub fns: HashMap<&'a str, (Vec<&'a str>, Node<'a>)>,
+    pub conversions: ConversionTable<'a>,
+    // decimal places and strategy division results are rounded to, None
+    // keeps the full precision of Decimal
+    pub rounding: Option<(u32, RoundingStrategy)>,
+    // unknown identifiers are errors, otherwise they evaluate to a free unit.
+    // The known unit names are the keys of base_units, which `def` fills
+    pub strict_units: bool,
+    // decimal places results are shown with, the stored values keep their
+    // full precision
+    pub display_precision: Option<u32>,
+    // number of user function calls this scope is nested in
+    pub depth: usize,
     pub parent: Option<Context<'a>>,
 }
 
@@ -16,27 +42,32 @@ impl<'a> ContextCore<'a> {
     pub fn assign(
         &mut self,
         name: &'a str,
-        mut value: Quantity<'a>,
+        value: Value<'a>,
         span: Range<usize>,
-    ) -> RuntimeResult<'a> {
+    ) -> Result<Value<'a>, MorphError<'a>> {
         if self.base_units.contains_key(name) {
             Err(MorphError::custom(
-                value.span,
+                span,
                 format!("base unit {} is not assignable", name),
                 ErrorType::UnsupportedAttribute,
             ))
         } else {
-            value.span = span;
+            let value = value.map(|q| Quantity {
+                span: span.clone(),
+                ..q
+            });
             self.vars.insert(name, value.clone());
             Ok(value)
         }
     }
 
-    pub fn var(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
-        let found = self.vars.get_mut(name).map(|x| {
-            let mut ret = x.clone();
-            ret.span = span.clone();
-            ret
+    pub fn var(&mut self, name: &'a str, span: Range<usize>) -> Result<Value<'a>, MorphError<'a>> {
+        let found = self.vars.get(name).map(|x| {
+            x.clone().map(|mut ret| {
+                ret.unit = ret.unit.with_span(span.clone());
+                ret.span = span.clone();
+                ret
+            })
         });
 
         if found.is_none() {
@@ -66,10 +97,52 @@ impl<'a> ContextCore<'a> {
         }
     }
 
+    pub fn define_fn(&mut self, name: &'a str, params: Vec<&'a str>, body: Node<'a>) {
+        self.fns.insert(name, (params, body));
+    }
+
+    pub fn func(&self, name: &'a str) -> Option<(Vec<&'a str>, Node<'a>)> {
+        match self.fns.get(name) {
+            Some(f) => Some(f.clone()),
+            None => self.parent.as_ref().and_then(|p| p.func(name)),
+        }
+    }
+
+    // conversions are registered on the root context
+    pub fn conversions(&self) -> ConversionTable<'a> {
+        match &self.parent {
+            Some(p) => p.conversions(),
+            None => self.conversions.clone(),
+        }
+    }
+
+    // so is the rounding of divisions
+    pub fn rounding(&self) -> Option<(u32, RoundingStrategy)> {
+        match &self.parent {
+            Some(p) => p.0.borrow().rounding(),
+            None => self.rounding,
+        }
+    }
+
+    pub fn display_precision(&self) -> Option<u32> {
+        match &self.parent {
+            Some(p) => p.0.borrow().display_precision(),
+            None => self.display_precision,
+        }
+    }
+
+    pub fn strict_units(&self) -> bool {
+        match &self.parent {
+            Some(p) => p.0.borrow().strict_units(),
+            None => self.strict_units,
+        }
+    }
+
     pub fn unit(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
         // self.base_units.get(name).map(|x| x.clone())
         let found = self.base_units.get_mut(name).map(|x| {
             let mut ret = x.clone();
+            ret.unit = ret.unit.with_span(span.clone());
             ret.span = span.clone();
             ret
         });
@@ -87,16 +160,24 @@ impl<'a> ContextCore<'a> {
         ))
     }
 
-    pub fn unit_or_var(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
+    pub fn unit_or_var(
+        &mut self,
+        name: &'a str,
+        span: Range<usize>,
+    ) -> Result<Value<'a>, MorphError<'a>> {
         if let Ok(x) = self.unit(name, span.clone()) {
-            Ok(x)
+            Ok(Value::Quantity(x))
         } else if let Ok(x) = self.var(name, span.clone()) {
             Ok(x)
+        } else if let Some((_, value)) = CONSTANTS.iter().find(|(c, _)| *c == name) {
+            Ok(Value::Quantity(Quantity::num(*value, span)))
+        } else if !self.strict_units() {
+            Ok(Value::Quantity(Quantity::base(name, span)))
         } else {
             Err(MorphError::custom(
                 span,
                 format!("use of undeclared unit/variable '{}'", name),
-                ErrorType::UndefinedIdent,
+                ErrorType::UnknownUnit,
             ))
         }
     }
@@ -107,19 +188,38 @@ pub struct Context<'a>(Rc<RefCell<ContextCore<'a>>>);
 
 impl<'a> Context<'a> {
     pub fn new() -> Self {
-        Context(Rc::new(RefCell::new(Default::default())))
+        let core = ContextCore {
+            strict_units: true,
+            ..Default::default()
+        };
+
+        Context(Rc::new(RefCell::new(core)))
     }
 
     pub fn from_parent(parent: Context<'a>) -> Self {
+        let depth = parent.depth();
         let core = ContextCore {
             base_units: HashMap::new(),
             vars: HashMap::new(),
+            fns: HashMap::new(),
+            conversions: ConversionTable::new(),
+            rounding: None,
+            strict_units: true,
+            display_precision: None,
+            depth,
             parent: Some(parent),
         };
 
         Context(Rc::new(RefCell::new(core)))
     }
 
+    // the scope a user function body runs in, one call deeper than its caller
+    pub fn for_call(parent: Context<'a>) -> Self {
+        let child = Context::from_parent(parent);
+        child.0.borrow_mut().depth += 1;
+        child
+    }
+
     #[allow(unused_assignments)]
     pub fn into_parent(mut self) {
         let parent = self.0.borrow().clone().parent;
@@ -133,13 +233,13 @@ impl<'a> Context<'a> {
     pub fn assign(
         &mut self,
         name: &'a str,
-        value: Quantity<'a>,
+        value: Value<'a>,
         span: Range<usize>,
-    ) -> RuntimeResult<'a> {
+    ) -> Result<Value<'a>, MorphError<'a>> {
         self.0.borrow_mut().assign(name, value, span)
     }
 
-    pub fn var(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
+    pub fn var(&mut self, name: &'a str, span: Range<usize>) -> Result<Value<'a>, MorphError<'a>> {
         self.0.borrow_mut().var(name, span)
     }
 
@@ -147,84 +247,1367 @@ impl<'a> Context<'a> {
         self.0.borrow_mut().define(name, span)
     }
 
+    pub fn define_fn(&mut self, name: &'a str, params: Vec<&'a str>, body: Node<'a>) {
+        self.0.borrow_mut().define_fn(name, params, body)
+    }
+
+    pub fn func(&self, name: &'a str) -> Option<(Vec<&'a str>, Node<'a>)> {
+        self.0.borrow().func(name)
+    }
+
+    pub fn conversions(&self) -> ConversionTable<'a> {
+        self.0.borrow().conversions()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.0.borrow().depth
+    }
+
+    pub fn set_rounding(&self, dp: u32, strategy: RoundingStrategy) {
+        self.0.borrow_mut().rounding = Some((dp, strategy));
+    }
+
+    pub fn set_strict_units(&self, strict: bool) {
+        self.0.borrow_mut().strict_units = strict;
+    }
+
+    pub fn set_display_precision(&self, dp: Option<u32>) {
+        self.0.borrow_mut().display_precision = dp;
+    }
+
+    pub fn display(&self, value: &Value<'a>) -> String {
+        let precision = self.0.borrow().display_precision();
+
+        match precision {
+            Some(dp) => value.round_dp(dp).to_string(),
+            None => value.to_string(),
+        }
+    }
+
+    pub fn round_division(&self, res: Value<'a>) -> Value<'a> {
+        let rounding = self.0.borrow().rounding();
+
+        match rounding {
+            Some((dp, strategy)) => {
+                res.map(|q| q.map_value(|v| v.round_dp_with_strategy(dp, strategy)))
+            }
+            None => res,
+        }
+    }
+
     pub fn unit(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
         self.0.borrow_mut().unit(name, span)
     }
 
-    pub fn unit_or_var(&mut self, name: &'a str, span: Range<usize>) -> RuntimeResult<'a> {
+    pub fn unit_or_var(
+        &mut self,
+        name: &'a str,
+        span: Range<usize>,
+    ) -> Result<Value<'a>, MorphError<'a>> {
         self.0.borrow_mut().unit_or_var(name, span)
     }
 }
 
+fn expect_args<'a, T, const N: usize>(
+    name: &str,
+    args: Vec<T>,
+    span: &Range<usize>,
+) -> Result<[T; N], MorphError<'a>> {
+    let found = args.len();
+
+    args.try_into().map_err(|_| {
+        MorphError::custom(
+            span.clone(),
+            format!("{} expects {} argument(s), found {}", name, N, found),
+            ErrorType::TypeError,
+        )
+    })
+}
+
+// returns the argument that compares as `keep` against all others, or the first of equals
+fn extreme<'a>(
+    name: &str,
+    args: Vec<Quantity<'a>>,
+    span: Range<usize>,
+    keep: Ordering,
+) -> RuntimeResult<'a> {
+    let mut args = args.into_iter();
+
+    let mut res = args.next().ok_or_else(|| {
+        MorphError::custom(
+            span.clone(),
+            format!("{} expects at least 1 argument, found 0", name),
+            ErrorType::TypeError,
+        )
+    })?;
+
+    for arg in args {
+        match arg.partial_cmp(&res) {
+            Some(ord) if ord == keep => res = arg,
+            Some(_) => {}
+            None => {
+                return Err(MorphError::custom(
+                    merge_span(&res.span, &arg.span),
+                    format!(
+                        "non-conformable units for '{}': ({}, {})",
+                        name, res.unit, arg.unit
+                    ),
+                    ErrorType::TypeError,
+                ))
+            }
+        }
+    }
+
+    Ok(Quantity { span, ..res })
+}
+
+fn angles() -> ConversionTable<'static> {
+    let mut table = ConversionTable::new();
+    table.register("deg", "rad", Decimal::PI / dec!(180));
+    table
+}
+
+// goes through f64, the result is rounded to 15 decimal places to hide the float noise
+fn trig<'a>(
+    name: &str,
+    args: Vec<Quantity<'a>>,
+    span: Range<usize>,
+    f: fn(f64) -> f64,
+) -> RuntimeResult<'a> {
+    let [x] = expect_args(name, args, &span)?;
+
+    let radians = if x.is_scalar() {
+        x.value
+    } else {
+        let rad = Unit::from(UnitAtom::base("rad"));
+        x.convert_to(&rad, &angles())
+            .map_err(|_| {
+                MorphError::custom(
+                    span.clone(),
+                    format!("{} expects an angle, found: {}", name, x),
+                    ErrorType::TypeError,
+                )
+            })?
+            .value
+    };
+
+    match radians.to_f64().map(f).and_then(Decimal::from_f64) {
+        Some(res) => Ok(Quantity::num(res.round_dp(15).normalize(), span)),
+        None => Err(MorphError::custom(
+            span,
+            format!("could not compute {}({})", name, x),
+            ErrorType::DomainError,
+        )),
+    }
+}
+
+// ln, exp and log(base, x) only take dimensionless arguments, log defaults to
+// base 10. They go through f64, so like trig the result keeps 15 decimals
+fn logarithmic<'a>(name: &str, args: Vec<Quantity<'a>>, span: Range<usize>) -> RuntimeResult<'a> {
+    let (base, x) = match (name, args.len()) {
+        ("log", 2) => {
+            let [base, x] = expect_args(name, args, &span)?;
+            (Some(base), x)
+        }
+        _ => {
+            let [x] = expect_args(name, args, &span)?;
+            (None, x)
+        }
+    };
+
+    for arg in base.iter().chain([&x]) {
+        if !arg.is_scalar() {
+            return Err(MorphError::custom(
+                arg.span.clone(),
+                format!("{} expects a dimensionless argument, found: {}", name, arg),
+                ErrorType::TypeError,
+            ));
+        }
+    }
+
+    let value = x.value.to_f64();
+    let res = match (name, base) {
+        ("ln", _) => value.map(f64::ln),
+        ("exp", _) => value.map(f64::exp),
+        (_, None) => value.map(f64::log10),
+        (_, Some(base)) => value.zip(base.value.to_f64()).map(|(x, b)| x.log(b)),
+    };
+
+    match res.filter(|r| r.is_finite()).and_then(Decimal::from_f64) {
+        Some(res) => Ok(Quantity::num(res.round_dp(15).normalize(), span)),
+        None => Err(MorphError::custom(
+            span,
+            format!("could not compute {}({})", name, x),
+            ErrorType::DomainError,
+        )),
+    }
+}
+
+fn builtin<'a>(name: &'a str, args: Vec<Quantity<'a>>, span: Range<usize>) -> RuntimeResult<'a> {
+    match name {
+        "sqrt" => {
+            let [x] = expect_args(name, args, &span)?;
+            Ok(Quantity { span, ..x.sqrt()? })
+        }
+        "abs" => {
+            let [x] = expect_args(name, args, &span)?;
+            Ok(Quantity { span, ..x.abs() })
+        }
+        "floor" | "ceil" | "round" | "trunc" => {
+            let [x] = expect_args(name, args, &span)?;
+            let res = match name {
+                "floor" => x.floor(),
+                "ceil" => x.ceil(),
+                "round" => x.round(),
+                _ => x.trunc(),
+            };
+            Ok(Quantity { span, ..res })
+        }
+        "ln" | "log" | "exp" => logarithmic(name, args, span),
+        "sin" => trig(name, args, span, f64::sin),
+        "cos" => trig(name, args, span, f64::cos),
+        "tan" => trig(name, args, span, f64::tan),
+        "min" => extreme(name, args, span, Ordering::Less),
+        "max" => extreme(name, args, span, Ordering::Greater),
+        _ => Err(MorphError::custom(
+            span,
+            format!("use of undeclared function '{}'", name),
+            ErrorType::UndefinedIdent,
+        )),
+    }
+}
+
+// sum and product fold a list with the quantity ops, an empty list gives
+// the dimensionless identity
+fn reduce<'a>(name: &str, list: Value<'a>, span: Range<usize>) -> RuntimeResult<'a> {
+    let items = match list {
+        Value::List(items) => items,
+        Value::Quantity(q) => {
+            return Err(MorphError::custom(
+                q.span.clone(),
+                format!("{} expects a list, found: {}", name, q),
+                ErrorType::TypeError,
+            ))
+        }
+    };
+
+    let (identity, op): (_, QuantityOp<'a>) = match name {
+        "sum" => (Quantity::zero(span.clone()), ops::Add::add),
+        _ => (Quantity::one(span.clone()), ops::Mul::mul),
+    };
+
+    let mut items = items.into_iter();
+    let res = match items.next() {
+        Some(first) => items.try_fold(first, op)?,
+        None => identity,
+    };
+
+    Ok(Quantity { span, ..res })
+}
+
+type BinopCtor<'a> = fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>;
+
+fn fold_binop<'a>(
+    lhs: Node<'a>,
+    rhs: Node<'a>,
+    ctor: BinopCtor<'a>,
+    op: fn(Decimal, Decimal) -> Option<Decimal>,
+) -> NodeType<'a> {
+    let lhs = fold_constants(lhs);
+    let rhs = fold_constants(rhs);
+
+    if let (Ok(l), Ok(r)) = (Decimal::try_from(&lhs), Decimal::try_from(&rhs)) {
+        // failing ops like division by zero are kept for eval to report
+        if let Some(res) = op(l, r) {
+            return NodeType::Num(res);
+        }
+    }
+
+    ctor(lhs.into(), rhs.into())
+}
+
+#[allow(dead_code)]
+pub fn fold_constants<'a>(node: Node<'a>) -> Node<'a> {
+    use NodeType::*;
+
+    let fold = |n: Box<Node<'a>>| Box::new(fold_constants(*n));
+
+    let typ = match node.typ {
+        Add(lhs, rhs) => fold_binop(*lhs, *rhs, Add, Decimal::checked_add),
+        Sub(lhs, rhs) => fold_binop(*lhs, *rhs, Sub, Decimal::checked_sub),
+        Mul(lhs, rhs) => fold_binop(*lhs, *rhs, Mul, Decimal::checked_mul),
+        Div(lhs, rhs) => fold_binop(*lhs, *rhs, Div, Decimal::checked_div),
+        Mod(lhs, rhs) => fold_binop(*lhs, *rhs, Mod, Decimal::checked_rem),
+        Pow(lhs, rhs) => fold_binop(*lhs, *rhs, Pow, checked_pow),
+        BitAnd(lhs, rhs) => fold_binop(*lhs, *rhs, BitAnd, |l, r| BitOp::And.checked(l, r)),
+        BitOr(lhs, rhs) => fold_binop(*lhs, *rhs, BitOr, |l, r| BitOp::Or.checked(l, r)),
+        Shl(lhs, rhs) => fold_binop(*lhs, *rhs, Shl, |l, r| BitOp::Shl.checked(l, r)),
+        Shr(lhs, rhs) => fold_binop(*lhs, *rhs, Shr, |l, r| BitOp::Shr.checked(l, r)),
+
+        Equal(lhs, rhs) => Equal(fold(lhs), fold(rhs)),
+        NeEqual(lhs, rhs) => NeEqual(fold(lhs), fold(rhs)),
+        GreaterEqual(lhs, rhs) => GreaterEqual(fold(lhs), fold(rhs)),
+        LesserEqual(lhs, rhs) => LesserEqual(fold(lhs), fold(rhs)),
+        Greater(lhs, rhs) => Greater(fold(lhs), fold(rhs)),
+        Lesser(lhs, rhs) => Lesser(fold(lhs), fold(rhs)),
+
+        UnryNot(val) => UnryNot(fold(val)),
+        UnrySub(val) => UnrySub(fold(val)),
+        Percent(val) => Percent(fold(val)),
+        Abs(val) => Abs(fold(val)),
+        Convert { expr, target } => Convert {
+            expr: fold(expr),
+            target,
+        },
+
+        Assign(name, val) => Assign(name, fold(val)),
+        AddAssign(name, val) => AddAssign(name, fold(val)),
+        SubAssign(name, val) => SubAssign(name, fold(val)),
+        MulAssign(name, val) => MulAssign(name, fold(val)),
+        DivAssign(name, val) => DivAssign(name, fold(val)),
+        PowAssign(name, val) => PowAssign(name, fold(val)),
+
+        IfElse {
+            cond,
+            if_body,
+            else_body,
+        } => IfElse {
+            cond: fold(cond),
+            if_body: fold(if_body),
+            else_body: else_body.map(fold),
+        },
+        FnCall { name, args } => FnCall {
+            name,
+            args: args.into_iter().map(fold_constants).collect(),
+        },
+        Scope(nodes) => Scope(nodes.into_iter().map(fold_constants).collect()),
+        List(items) => List(items.into_iter().map(fold_constants).collect()),
+        DefFn { name, params, body } => DefFn {
+            name,
+            params,
+            body: fold(body),
+        },
+
+        typ @ (Def(_) | Unit(_) | Num(_) | ParseError) => typ,
+    };
+
+    Node::new(typ, node.span)
+}
+
+type QuantityOp<'a> = fn(Quantity<'a>, Quantity<'a>) -> RuntimeResult<'a>;
+
+// '+' and '-' pair up the elements of two lists, '*' and '/' apply a
+// quantity to every element
+fn list_binop<'a>(
+    lhs: Value<'a>,
+    rhs: Value<'a>,
+    sym: &str,
+    op: QuantityOp<'a>,
+    span: Range<usize>,
+) -> Result<Value<'a>, MorphError<'a>> {
+    let elementwise = matches!(sym, "+" | "-");
+    let err = |msg: String| Err(MorphError::custom(span.clone(), msg, ErrorType::TypeError));
+
+    let items: Result<Vec<_>, _> = match (lhs, rhs) {
+        (Value::Quantity(lhs), Value::Quantity(rhs)) => return op(lhs, rhs).map(Value::Quantity),
+        (Value::List(lhs), Value::List(rhs)) if elementwise => {
+            if lhs.len() != rhs.len() {
+                return err(format!(
+                    "list lengths differ for '{}': {} and {}",
+                    sym,
+                    lhs.len(),
+                    rhs.len()
+                ));
+            }
+
+            if let Some(first) = lhs.first() {
+                if !lhs
+                    .iter()
+                    .chain(&rhs)
+                    .all(|q| q.unit.same_dimension(&first.unit))
+                {
+                    return err(format!("list elements must share a unit for '{}'", sym));
+                }
+            }
+
+            lhs.into_iter().zip(rhs).map(|(l, r)| op(l, r)).collect()
+        }
+        (Value::List(lhs), Value::Quantity(rhs)) if !elementwise => {
+            lhs.into_iter().map(|l| op(l, rhs.clone())).collect()
+        }
+        (Value::Quantity(lhs), Value::List(rhs)) if !elementwise => {
+            rhs.into_iter().map(|r| op(lhs.clone(), r)).collect()
+        }
+        (Value::List(_), Value::List(_)) => {
+            return err(format!("'{}' needs a quantity on one side of a list", sym))
+        }
+        _ => return err(format!("'{}' can only combine a list with a list", sym)),
+    };
+
+    items.map(Value::List)
+}
+
+// lists only reach the operations above, everything else needs a quantity
+fn quantity<'a>(value: Value<'a>, span: Range<usize>) -> RuntimeResult<'a> {
+    match value {
+        Value::Quantity(q) => Ok(q),
+        Value::List(_) => Err(MorphError::custom(
+            span,
+            "expected a quantity, found a list",
+            ErrorType::TypeError,
+        )),
+    }
+}
+
+// records the span of the first parse error in source order
+#[derive(Default)]
+struct ErrFinder(Option<Range<usize>>);
+
+impl<'a> Visitor<'a> for ErrFinder {
+    fn visit_node(&mut self, n: &Node<'a>) {
+        if self.0.is_some() {
+            return;
+        }
+
+        match n.is_err() {
+            true => self.0 = Some(n.span.clone()),
+            false => walk_node(self, n),
+        }
+    }
+}
+
 impl<'a> Node<'a> {
-    pub fn eval(self, mut cntxt: Context<'a>) -> RuntimeResult<'a> {
+    // a tree holding a parse error is rejected up front so no statement
+    // before it runs and leaves a partial result behind
+    pub fn eval(self, cntxt: Context<'a>) -> RuntimeResult<'a> {
+        self.reject_parse_errors()?;
+        self.eval_quantity(cntxt)
+    }
+
+    // like eval, but the result may also be a list
+    pub fn eval_value(self, cntxt: Context<'a>) -> Result<Value<'a>, MorphError<'a>> {
+        self.reject_parse_errors()?;
+        self.eval_checked(cntxt)
+    }
+
+    fn reject_parse_errors(&self) -> Result<(), MorphError<'a>> {
+        let mut finder = ErrFinder::default();
+        finder.visit_node(self);
+
+        match finder.0 {
+            Some(span) => Err(MorphError::custom(
+                span,
+                "encountered error from parse stage",
+                ErrorType::UndefinedSyntax,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn eval_quantity(self, cntxt: Context<'a>) -> RuntimeResult<'a> {
+        let span = self.span.clone();
+        quantity(self.eval_checked(cntxt)?, span)
+    }
+
+    fn eval_checked(self, mut cntxt: Context<'a>) -> Result<Value<'a>, MorphError<'a>> {
         use NodeType::*;
 
+        let span = self.span;
+        let q = |n: Box<Node<'a>>| n.eval_quantity(cntxt.clone());
+        let binop = |lhs: Box<Node<'a>>, rhs: Box<Node<'a>>, sym, op| {
+            let lhs = lhs.eval_checked(cntxt.clone())?;
+            let rhs = rhs.eval_checked(cntxt.clone())?;
+            list_binop(lhs, rhs, sym, op, span.clone())
+        };
+        // a zero divisor is reported at its whole operand, parentheses included
+        let divisor = |n: Box<Node<'a>>| {
+            let outer = n.span.clone();
+            let value = n.eval_checked(cntxt.clone())?;
+            Ok::<_, MorphError<'a>>(value.map(|q| Quantity {
+                span: outer.clone(),
+                ..q
+            }))
+        };
+        let update = |name, rhs: Box<Node<'a>>, sym, op| {
+            let var = cntxt.clone().var(name, span.clone())?;
+            let rhs = rhs.eval_checked(cntxt.clone())?;
+            list_binop(var, rhs, sym, op, span.clone())
+        };
+
         match self.typ {
-            Def(name) => cntxt.define(name, self.span),
-            IfElse { .. } => todo!(),
-            Add(lhs, rhs) => lhs.eval(cntxt.clone())? + rhs.eval(cntxt)?,
-            Sub(lhs, rhs) => lhs.eval(cntxt.clone())? - rhs.eval(cntxt)?,
-            Mul(lhs, rhs) => lhs.eval(cntxt.clone())? * rhs.eval(cntxt)?,
-            Div(lhs, rhs) => lhs.eval(cntxt.clone())? / rhs.eval(cntxt)?,
-            Pow(lhs, rhs) => lhs.eval(cntxt.clone())? ^ rhs.eval(cntxt)?,
-            UnryNot(val) => val.eval(cntxt.clone())?.not(),
-            UnrySub(val) => {
-                let mut v = val.eval(cntxt.clone())?;
-                v.value *= dec!(-1);
-                Ok(v)
-            }
-            Unit(name) => cntxt.unit_or_var(name, self.span),
-            Num(num) => Ok(Quantity::num(num, self.span)),
+            Def(name) => cntxt.define(name, span).map(Value::Quantity),
+            DefFn { name, params, body } => {
+                cntxt.define_fn(name, params, *body);
+                Ok(Value::Quantity(Quantity::zero(span)))
+            }
+            IfElse {
+                cond,
+                if_body,
+                else_body,
+            } => {
+                let cond = cond.eval_quantity(cntxt.clone())?;
+
+                if !cond.is_scalar() {
+                    return Err(MorphError::custom(
+                        cond.span.clone(),
+                        format!("condition must be dimensionless, found: {}", cond),
+                        ErrorType::TypeError,
+                    ));
+                }
+
+                match (cond.is_zero(), else_body) {
+                    (false, _) => if_body.eval_checked(cntxt),
+                    (true, Some(body)) => body.eval_checked(cntxt),
+                    (true, None) => Ok(Value::Quantity(Quantity::zero(span))),
+                }
+            }
+            Add(lhs, rhs) => binop(lhs, rhs, "+", ops::Add::add),
+            Sub(lhs, rhs) => binop(lhs, rhs, "-", ops::Sub::sub),
+            Mul(lhs, rhs) => binop(lhs, rhs, "*", ops::Mul::mul),
+            Div(lhs, rhs) => {
+                let lhs = lhs.eval_checked(cntxt.clone())?;
+                let res = list_binop(lhs, divisor(rhs)?, "/", ops::Div::div, span.clone())?;
+                Ok(cntxt.round_division(res))
+            }
+            Mod(lhs, rhs) => {
+                let outer = rhs.span.clone();
+                (q(lhs)? % quantity(divisor(rhs)?, outer)?).map(Value::Quantity)
+            }
+            Pow(lhs, rhs) => (q(lhs)? ^ q(rhs)?).map(Value::Quantity),
+            BitAnd(lhs, rhs) => q(lhs)?.bitwise(q(rhs)?, BitOp::And).map(Value::Quantity),
+            BitOr(lhs, rhs) => q(lhs)?.bitwise(q(rhs)?, BitOp::Or).map(Value::Quantity),
+            Shl(lhs, rhs) => q(lhs)?.bitwise(q(rhs)?, BitOp::Shl).map(Value::Quantity),
+            Shr(lhs, rhs) => q(lhs)?.bitwise(q(rhs)?, BitOp::Shr).map(Value::Quantity),
+            UnryNot(val) => q(val)?.not().map(Value::Quantity),
+            UnrySub(val) => Ok(Value::Quantity(-q(val)?)),
+            Abs(val) => {
+                let mut res = q(val)?.abs();
+                res.span = span;
+                Ok(Value::Quantity(res))
+            }
+            // a list is converted element by element
+            Convert { expr, target } => {
+                let value = expr.eval_checked(cntxt.clone())?;
+                let mut table = cntxt.conversions();
+
+                value.try_map(|mut res| {
+                    res.span = span.clone();
+                    table.register_prefixes(&res.unit);
+                    table.register_prefixes(&target);
+                    res.convert_to(&target, &table)
+                })
+            }
+            Percent(val) => {
+                let mut res = q(val)?;
+                res.value /= dec!(100);
+                res.span = span;
+                Ok(Value::Quantity(res))
+            }
+            Unit(name) => cntxt.unit_or_var(name, span),
+            Num(num) => Ok(Value::Quantity(Quantity::num(num, span))),
+            FnCall { name, args } => {
+                let func = cntxt.func(name);
+                if matches!(name, "sum" | "product") && func.is_none() {
+                    let [list] = expect_args(name, args, &span)?;
+                    return reduce(name, list.eval_checked(cntxt)?, span).map(Value::Quantity);
+                }
+
+                let args = args
+                    .into_iter()
+                    .map(|arg| {
+                        let span = arg.span.clone();
+                        Ok((arg.eval_checked(cntxt.clone())?, span))
+                    })
+                    .collect::<Result<Vec<_>, MorphError<'a>>>()?;
+
+                match func {
+                    Some((params, body)) => {
+                        if params.len() != args.len() {
+                            return Err(MorphError::custom(
+                                span,
+                                format!(
+                                    "{} expects {} argument(s), found {}",
+                                    name,
+                                    params.len(),
+                                    args.len()
+                                ),
+                                ErrorType::TypeError,
+                            ));
+                        }
+
+                        if cntxt.depth() >= MAX_CALL_DEPTH {
+                            return Err(MorphError::custom(
+                                span,
+                                format!("{} recurses deeper than {} calls", name, MAX_CALL_DEPTH),
+                                ErrorType::TooDeep,
+                            ));
+                        }
+
+                        // arguments are bound in a child scope of the caller
+                        let mut child = Context::for_call(cntxt);
+                        for (param, (arg, span)) in params.into_iter().zip(args) {
+                            child.assign(param, arg, span)?;
+                        }
+
+                        body.eval_checked(child)
+                    }
+                    // the builtins only take quantities
+                    None => {
+                        let args = args
+                            .into_iter()
+                            .map(|(arg, span)| quantity(arg, span))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        builtin(name, args, span).map(Value::Quantity)
+                    }
+                }
+            }
             Assign(name, val) => {
-                let val = val.eval(cntxt.clone())?;
-                cntxt.assign(name, val, self.span)
-            }
-            AddAssign(lhs, rhs) => {
-                let var = cntxt.var(lhs, self.span.clone())?;
-                let res = var + rhs.eval(cntxt.clone())?;
-                cntxt.assign(lhs, res?, self.span)
-            }
-            SubAssign(lhs, rhs) => {
-                let var = cntxt.var(lhs, self.span.clone())?;
-                let res = var - rhs.eval(cntxt.clone())?;
-                cntxt.assign(lhs, res?, self.span)
-            }
-            MulAssign(lhs, rhs) => {
-                let var = cntxt.var(lhs, self.span.clone())?;
-                let res = var * rhs.eval(cntxt.clone())?;
-                cntxt.assign(lhs, res?, self.span)
-            }
-            DivAssign(lhs, rhs) => {
-                let var = cntxt.var(lhs, self.span.clone())?;
-                let res = var / rhs.eval(cntxt.clone())?;
-                cntxt.assign(lhs, res?, self.span)
-            }
-            Equal(lhs, rhs) => lhs.eval(cntxt.clone())?.equal(&rhs.eval(cntxt)?),
-            NeEqual(lhs, rhs) => (lhs.eval(cntxt.clone())?.equal(&rhs.eval(cntxt)?))?.not(),
-            GreaterEqual(lhs, rhs) => lhs.eval(cntxt.clone())?.greater_eq(&rhs.eval(cntxt)?),
-            LesserEqual(lhs, rhs) => lhs.eval(cntxt.clone())?.lesser_eq(&rhs.eval(cntxt)?),
-            Greater(lhs, rhs) => lhs.eval(cntxt.clone())?.greater(&rhs.eval(cntxt)?),
-            Lesser(lhs, rhs) => lhs.eval(cntxt.clone())?.lesser(&rhs.eval(cntxt)?),
+                let val = val.eval_checked(cntxt.clone())?;
+                cntxt.assign(name, val, span)
+            }
+            AddAssign(name, rhs) => {
+                let res = update(name, rhs, "+", ops::Add::add)?;
+                cntxt.assign(name, res, span)
+            }
+            SubAssign(name, rhs) => {
+                let res = update(name, rhs, "-", ops::Sub::sub)?;
+                cntxt.assign(name, res, span)
+            }
+            MulAssign(name, rhs) => {
+                let res = update(name, rhs, "*", ops::Mul::mul)?;
+                cntxt.assign(name, res, span)
+            }
+            DivAssign(name, rhs) => {
+                let var = cntxt.clone().var(name, span.clone())?;
+                let res = list_binop(var, divisor(rhs)?, "/", ops::Div::div, span.clone())?;
+                cntxt.assign(name, cntxt.round_division(res), span)
+            }
+            PowAssign(name, rhs) => {
+                let var = quantity(cntxt.clone().var(name, span.clone())?, span.clone())?;
+                let res = (var ^ q(rhs)?)?;
+                cntxt.assign(name, Value::Quantity(res), span)
+            }
+            Equal(lhs, rhs) => q(lhs)?.equal(&q(rhs)?).map(Value::Quantity),
+            NeEqual(lhs, rhs) => q(lhs)?.equal(&q(rhs)?)?.not().map(Value::Quantity),
+            GreaterEqual(lhs, rhs) => q(lhs)?.greater_eq(&q(rhs)?).map(Value::Quantity),
+            LesserEqual(lhs, rhs) => q(lhs)?.lesser_eq(&q(rhs)?).map(Value::Quantity),
+            Greater(lhs, rhs) => q(lhs)?.greater(&q(rhs)?).map(Value::Quantity),
+            Lesser(lhs, rhs) => q(lhs)?.lesser(&q(rhs)?).map(Value::Quantity),
 
             Scope(vec) => {
-                let mut res: Option<RuntimeResult<'a>> = None;
+                let mut res = None;
 
                 cntxt = Context::from_parent(cntxt);
 
                 for e in vec {
-                    res = Some(Ok(e.eval(cntxt.clone())?));
+                    res = Some(e.eval_checked(cntxt.clone())?);
                 }
 
                 cntxt.into_parent();
 
-                res.unwrap_or(Ok(Quantity::num(0, self.span)))
+                Ok(res.unwrap_or(Value::Quantity(Quantity::zero(span))))
             }
+            List(items) => items
+                .into_iter()
+                .map(|item| item.eval_quantity(cntxt.clone()))
+                .collect::<Result<_, _>>()
+                .map(Value::List),
             ParseError => Err(MorphError::custom(
-                self.span,
+                span,
                 "encountered error from parse stage",
                 ErrorType::UndefinedSyntax,
             )),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{fold_constants, Context, DIMENSIONLESS_FNS, MAX_CALL_DEPTH, UNIT_PRESERVING_FNS};
+    use crate::{error::*, morph::test_utils::*, types::*};
+
+    use rust_decimal::{Decimal, RoundingStrategy};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn parse_error_short_circuits() {
+        let assign = Node::new(NodeType::Assign("x", n(1).into()), 0..5);
+        let stmt = u("x") + Node::err(11..14);
+        let root = Node::new(bod!(assign, stmt, Node::err(16..18)), 0..18);
+
+        let err = root.eval(Context::new()).unwrap_err();
+        eq!(err.span.into_range(), 11..14);
+        eq!(err.typ, ErrorType::UndefinedSyntax);
+
+        assert!(Node::err(0..1).is_err());
+        assert!(!n(1).is_err());
+    }
+
+    #[test]
+    fn compare() {
+        eq!(eval("1 < 2").unwrap().value, dec!(1));
+        eq!(eval("2 <= 1").unwrap().value, dec!(0));
+        eq!(eval("def m; 3 m == 3 m").unwrap().value, dec!(1));
+        eq!(eval("def m; 3 m != 4 m").unwrap().value, dec!(1));
+
+        let err = eval("def m; def s; 3 m == 3 s").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(
+            err.to_string(),
+            "non-conformable units for '==': ([m] == [s])"
+        );
+        eq!(
+            eval("def m; def s; 3 m > 3 s").unwrap_err().typ,
+            ErrorType::TypeError
+        );
+    }
+
+    #[test]
+    fn dimensionless() {
+        let res = eval("def m; (6 m) / (2 m)").unwrap();
+        assert!(res.is_scalar());
+        eq!(res.to_string(), "3");
+
+        let res = eval("def m; (6 m) / 2").unwrap();
+        assert!(!res.is_scalar());
+        eq!(res.to_string(), "3 [m]");
+    }
+
+    #[test]
+    fn comment() {
+        eq!(eval("x = 2 # two\n# skipped\nx * 3").unwrap().value, dec!(6));
+    }
+
+    #[test]
+    fn sqrt() {
+        eq!(eval("sqrt(9)").unwrap().value, dec!(3));
+        eq!(eval("def m; sqrt(4 * m^2)").unwrap().to_string(), "2 [m]");
+        eq!(eval("sqrt(-1)").unwrap_err().typ, ErrorType::DomainError);
+        eq!(eval("sqrt(1, 2)").unwrap_err().typ, ErrorType::TypeError);
+        eq!(eval("foo(1)").unwrap_err().typ, ErrorType::UndefinedIdent);
+    }
+
+    #[test]
+    fn unary_plus() {
+        eq!(eval("+5").unwrap().value, dec!(5));
+        eq!(eval("def m; +m == m").unwrap().value, dec!(1));
+        eq!(eval("def m; +m").unwrap().to_string(), "1 [m]");
+    }
+
+    #[test]
+    fn scope() {
+        eq!(eval("{ x = 5; x += 2; x }").unwrap().value, dec!(7));
+        eq!(eval("x = 3; { x *= 2; x }").unwrap().value, dec!(6));
+        eq!(eval("{ a = 2; a * 3 }").unwrap().value, dec!(6));
+        eq!(eval("{}").unwrap(), Quantity::zero(0..0));
+        eq!(eval("x = 3; { x = 10 }; x").unwrap().value, dec!(3));
+        eq!(eval("x = 3; { x *= 2 }; x").unwrap().value, dec!(3));
+        eq!(eval("{ a = 1; { a = 2; a } + a }").unwrap().value, dec!(3));
+        eq!(
+            eval("x = 3; { y = 1 }; y").unwrap_err().typ,
+            ErrorType::UnknownUnit
+        );
+
+        let err = eval("y += 1").unwrap_err();
+        eq!(err.typ, ErrorType::UndefinedIdent);
+        eq!(err.to_string(), "use of undeclared variable 'y'");
+    }
+
+    #[test]
+    fn assign_ops() {
+        let show = |src| eval(src).unwrap().to_string();
+
+        eq!(show("def m; x = 2 m; x += 3 m"), "5 [m]");
+        eq!(show("def m; x = 2 m; x -= 3 m; x"), "-1 [m]");
+        eq!(show("def m; x = 2 m; x *= 4 m"), "8 [m^2]");
+        eq!(show("def m; x = 2 m; x /= 4 m"), "0.5");
+        eq!(show("def m; x = 2 m; x ^= 2"), "4 [m^2]");
+        // a zero keeps its unit
+        eq!(show("def m; 0 m + 1 m"), "1 [m]");
+        eq!(show("def m; x = 0 m; x += 2 m"), "2 [m]");
+        eq!(show("def m; def s; 0 m * 3 s"), "0 [m s]");
+
+        let err = eval("def m; def s; x = 2 m; x += 3 s").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 30..31);
+        eq!(
+            err.to_string(),
+            "non-conformable units for '+': ([m] + [s])"
+        );
+
+        eq!(eval("x = y = 2; x + y").unwrap().value, dec!(4));
+
+        let err = eval("y /= 2").unwrap_err();
+        eq!(err.typ, ErrorType::UndefinedIdent);
+        eq!(err.span.into_range(), 0..6);
+    }
+
+    #[test]
+    fn percent() {
+        eq!(eval("10%").unwrap().value, dec!(0.1));
+        eq!(eval("(20)%").unwrap().value, dec!(0.2));
+        eq!(eval("200 * 10%").unwrap().value, dec!(20));
+        eq!(eval("def m; 5 m%").unwrap().to_string(), "0.05 [m]");
+    }
+
+    #[test]
+    fn constant_folding() {
+        let fold = |code| fold_constants(parse(code).0.unwrap());
+
+        eq!(fold("2 * (3 + 4)").typ, bod!(n(14)));
+
+        let root = fold("2 * 3 + 4 * 5");
+        eq!(root.typ, bod!(n(26)));
+        let NodeType::Scope(stmts) = root.typ else {
+            unreachable!()
+        };
+        eq!(stmts[0].span, 0..13);
+
+        eq!(fold("2 * x").typ, bod!(n(2) * u("x")));
+        eq!(
+            fold("y = x * (2 ^ 3 - 1); f(1 + 1)").typ,
+            bod!(
+                Node::new(NodeType::Assign("y", (u("x") * n(7)).into()), 0..0),
+                call("f", vec![n(2)])
+            )
+        );
+        eq!(fold("1 / 0").typ, bod!(n(1) / n(0)));
+        eq!(fold("(1 + 1) / (2 - 2)").typ, bod!(n(2) / n(0)));
+    }
+
+    #[test]
+    fn if_else() {
+        eq!(eval("if 1 < 2 { 3 } else { 4 }").unwrap().value, dec!(3));
+        eq!(eval("if 1 > 2 { 3 } else { 4 }").unwrap().value, dec!(4));
+        eq!(eval("x = 0.5; if x { x * 2 }").unwrap().value, dec!(1));
+        eq!(eval("if 0 { 3 }").unwrap(), Quantity::zero(0..0));
+
+        let err = eval("def m; if 2 m { 3 }").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(
+            err.to_string(),
+            "condition must be dimensionless, found: 2 [m]"
+        );
+    }
+
+    #[test]
+    fn ternary() {
+        eq!(eval("1 ? 5 : 6").unwrap().value, dec!(5));
+        eq!(eval("(1 ? 5 : 6) == 5").unwrap().value, dec!(1));
+        eq!(eval("0 ? 5 : 6").unwrap().value, dec!(6));
+        eq!(eval("0 ? 1 : 0 ? 2 : 3").unwrap().value, dec!(3));
+        eq!(eval("x = 4; x > 3 ? x * 2 : x").unwrap().value, dec!(8));
+
+        let err = eval("def m; 2 m ? 1 : 0").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn list() {
+        let value = |src| parse(src).0.unwrap().eval_value(Context::new());
+        let show = |src| value(src).unwrap().to_string();
+
+        eq!(show("[1, 2, 3]"), "[1, 2, 3]");
+        eq!(show("[1, 2] + [3, 4]"), "[4, 6]");
+        eq!(show("def m; [3 m, 2 m] - [1 m, 1 m]"), "[2 [m], 1 [m]]");
+        eq!(show("[1, 2, 3] * 2"), "[2, 4, 6]");
+        eq!(show("def m; 2 m * [1, 2]"), "[2 [m], 4 [m]]");
+        eq!(show("[2, 4] / 2 + [1, 1]"), "[2, 3]");
+        eq!(show("x = 2; [x, x * 2]"), "[2, 4]");
+        eq!(show("1 + 2"), "3");
+
+        let err = value("[1, 2] + [1]").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 0..12);
+        eq!(err.to_string(), "list lengths differ for '+': 2 and 1");
+
+        let err = value("def m; def s; [1 m, 2 s] + [1 m, 2 s]").unwrap_err();
+        eq!(err.to_string(), "list elements must share a unit for '+'");
+
+        let err = value("[1] + 1").unwrap_err();
+        eq!(err.to_string(), "'+' can only combine a list with a list");
+        let err = value("[1] * [2]").unwrap_err();
+        eq!(
+            err.to_string(),
+            "'*' needs a quantity on one side of a list"
+        );
+
+        // lists are values like any other, only the quantity operations
+        // reject them
+        eq!(show("x = [1, 2]; x + [1, 1]"), "[2, 3]");
+        eq!(show("x = [1, 2]; x *= 3; x"), "[3, 6]");
+        eq!(show("def m; [1 m, 2 m] : km"), "[0.001 [km], 0.002 [km]]");
+        eq!(show("def twice(x) { x * 2 }; twice([1, 2])"), "[2, 4]");
+        eq!(show("if 1 { [1] } else { 2 }"), "[1]");
+        eq!(show("xs = [1, 2]; sum(xs)"), "3");
+
+        let err = value("sqrt([4])").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 5..8);
+        eq!(err.to_string(), "expected a quantity, found a list");
+        eq!(
+            value("x = [1]; x ^= 2").unwrap_err().typ,
+            ErrorType::TypeError
+        );
+        eq!(value("-[1]").unwrap_err().typ, ErrorType::TypeError);
+        eq!(eval("[1]").unwrap_err().typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn division_rounding() {
+        let divide = |src, rounding: Option<(u32, RoundingStrategy)>| {
+            let cntxt = Context::new();
+            if let Some((dp, strategy)) = rounding {
+                cntxt.set_rounding(dp, strategy);
+            }
+            parse(src).0.unwrap().eval(cntxt).unwrap().value
+        };
+
+        let truncate = Some((4, RoundingStrategy::ToZero));
+        let half_up = Some((4, RoundingStrategy::MidpointAwayFromZero));
+        let half_even = Some((1, RoundingStrategy::MidpointNearestEven));
+
+        eq!(divide("1/3", None), dec!(0.3333333333333333333333333333));
+        eq!(divide("1/3", truncate), dec!(0.3333));
+        eq!(divide("1/3", half_up), dec!(0.3333));
+        eq!(divide("2/3", truncate), dec!(0.6666));
+        eq!(divide("2/3", half_up), dec!(0.6667));
+        eq!(divide("1/4", half_even), dec!(0.2));
+        eq!(divide("3/4", half_even), dec!(0.8));
+        eq!(divide("x = 2; { x /= 3 }", truncate), dec!(0.6666));
+        // only divisions are rounded
+        eq!(divide("1.23456 * 1", truncate), dec!(1.23456));
+    }
+
+    #[test]
+    fn bitwise() {
+        let value = |src| eval(src).unwrap().value;
+
+        eq!(value("5 & 3"), dec!(1));
+        eq!(value("5 & 3 == 1"), dec!(1));
+        eq!(value("5 | 3"), dec!(7));
+        eq!(value("8 | 1"), dec!(9));
+        eq!(value("1 << 4"), dec!(16));
+        eq!(value("-8 >> 1"), dec!(-4));
+        eq!(value("1 << -1 + 3"), dec!(4));
+        eq!(value("16 >> -2"), dec!(64));
+        eq!(value("6.0 & 3"), dec!(2));
+        eq!(value("|-5| | 2"), dec!(7));
+        eq!(value("5 | (-3)"), dec!(-3));
+
+        let err = eval("2.5 & 1").unwrap_err();
+        eq!(err.typ, ErrorType::DomainError);
+        eq!(err.span.into_range(), 0..3);
+        eq!(err.to_string(), "'&' needs whole numbers, found: 2.5");
+
+        let err = eval("def m; 1 | 2 m").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 11..14);
+        eq!(
+            err.to_string(),
+            "'|' needs dimensionless operands, found: 2 [m]"
+        );
+
+        eq!(eval("1 << 100").unwrap_err().typ, ErrorType::Overflow);
+        eq!(
+            fold_constants(parse("x = 12 & 10").0.unwrap()).to_string(),
+            "{\n(x = 8)\n}"
+        );
+    }
+
+    #[test]
+    fn approx_eq() {
+        let two = Quantity::num(dec!(2), 0..0);
+        let eps = dec!(0.000000001);
+        let table = ConversionTable::new();
+
+        let squared = eval("sqrt(2)^2").unwrap();
+        assert!(squared.approx_eq(&two, eps, &table));
+        assert!(!eval("sqrt(2)^2 + 0.1")
+            .unwrap()
+            .approx_eq(&two, eps, &table));
+
+        let metres = eval("def m; 2 m").unwrap();
+        let seconds = eval("def s; 2 s").unwrap();
+        let squared = eval("def m; sqrt(2)^2 * m").unwrap();
+        assert!(metres.approx_eq(&squared, eps, &table));
+        assert!(!metres.approx_eq(&seconds, dec!(1000), &table));
+        assert!(!metres.approx_eq(&two, dec!(1000), &table));
+
+        // units are compared by dimension, not by name
+        let km = eval("def m; 2 m : km").unwrap();
+        assert!(metres.approx_eq(&km, eps, &table));
+        assert!(eval("def m; def s; 2 m * s").unwrap().approx_eq(
+            &eval("def m; def s; 2 s * m").unwrap(),
+            eps,
+            &table
+        ));
+
+        // the difference overflows instead of panicking
+        let max = Quantity::num(Decimal::MAX, 0..0);
+        let min = Quantity::num(Decimal::MIN, 0..0);
+        assert!(!max.approx_eq(&min, eps, &table));
+    }
+
+    #[test]
+    fn strict_units() {
+        let run = |src, strict| {
+            let cntxt = Context::new();
+            cntxt.set_strict_units(strict);
+            parse(src).0.unwrap().eval(cntxt)
+        };
+
+        let err = run("5 metre", true).unwrap_err();
+        eq!(err.typ, ErrorType::UnknownUnit);
+        eq!(err.span.into_range(), 2..7);
+        eq!(err.to_string(), "use of undeclared unit/variable 'metre'");
+
+        eq!(run("5 metre", false).unwrap().to_string(), "5 [metre]");
+        eq!(
+            run("{ 2 metre } * 3 metre", false).unwrap().to_string(),
+            "6 [metre^2]"
+        );
+        eq!(
+            run("def m; 2 m + 3 metre", false).unwrap_err().typ,
+            ErrorType::TypeError
+        );
+
+        // bound names still win over free units
+        eq!(run("metre = 4; 5 * metre", false).unwrap().value, dec!(20));
+        eq!(run("2 * pi", false).unwrap().value, dec!(2) * Decimal::PI);
+    }
+
+    #[test]
+    fn constants() {
+        let pi = eval("pi").unwrap();
+        assert!((pi.value - dec!(3.14159)).abs() < dec!(0.00001));
+        assert!(pi.is_scalar());
+        eq!(eval("2 * pi").unwrap().value, dec!(2) * Decimal::PI);
+        eq!(eval("2 pi").unwrap().value, dec!(2) * Decimal::PI);
+        eq!(eval("e").unwrap().value, Decimal::E);
+
+        eq!(eval("pi = 3; 2 * pi").unwrap().value, dec!(6));
+        eq!(eval("{ pi = 3 }; pi").unwrap().value, Decimal::PI);
+        eq!(eval("def e; 2 e").unwrap().to_string(), "2 [e]");
+    }
+
+    #[test]
+    fn builtin_names() {
+        for name in UNIT_PRESERVING_FNS {
+            eq!(
+                eval(&format!("def m; {}(2 m)", name)).unwrap().to_string(),
+                "2 [m]"
+            );
+        }
+        for name in DIMENSIONLESS_FNS {
+            assert!(eval(&format!("{}(2)", name)).unwrap().is_scalar());
+        }
+    }
+
+    #[test]
+    fn reductions() {
+        eq!(
+            eval("def m; sum([1 m, 2 m, 3 m]) == 6 m").unwrap().value,
+            dec!(1)
+        );
+        eq!(eval("product([2, 3, 4])").unwrap().value, dec!(24));
+        eq!(
+            eval("def m; def s; product([2 m, 3 s, 1 m])")
+                .unwrap()
+                .to_string(),
+            "6 [m^2 s]"
+        );
+        eq!(eval("sum([1, 2] * 2)").unwrap().value, dec!(6));
+        eq!(eval("sum([])").unwrap(), Quantity::zero(0..0));
+        eq!(eval("product([])").unwrap(), Quantity::one(0..0));
+        eq!(eval("sum([])").unwrap().span, 0..7);
+        eq!(eval("def sum(x) { x + 1 }; sum(1)").unwrap().value, dec!(2));
+
+        let err = eval("def m; def s; sum([1 m, 2 s])").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 26..27);
+
+        let err = eval("sum(3)").unwrap_err();
+        eq!(err.to_string(), "sum expects a list, found: 3");
+        eq!(eval("sum([1], [2])").unwrap_err().typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn modulo() {
+        eq!(eval("7 mod 3 == 1").unwrap().value, dec!(1));
+        eq!(eval("def m; 7 m mod 3 m").unwrap().to_string(), "1 [m]");
+        eq!(
+            eval("def m; def s; 7 m mod 3 s").unwrap_err().typ,
+            ErrorType::TypeError
+        );
+        eq!(eval("7 mod 0").unwrap_err().typ, ErrorType::ZeroDivision);
+    }
+
+    #[test]
+    fn min_max() {
+        eq!(
+            eval("def m; max(3 m, 5 m, 1 m) == 5 m").unwrap().value,
+            dec!(1)
+        );
+        eq!(
+            eval("def m; min(3 m, 5 m, 1 m) == 1 m").unwrap().value,
+            dec!(1)
+        );
+        eq!(eval("min(4)").unwrap().value, dec!(4));
+        eq!(eval("max(-1, -2)").unwrap().value, dec!(-1));
+
+        let err = eval("def m; def s; max(3 m, 5 s)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(
+            err.to_string(),
+            "non-conformable units for 'max': ([m], [s])"
+        );
+        eq!(eval("min()").unwrap_err().typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn abs() {
+        eq!(eval("def m; |-3 m| == 3 m").unwrap().value, dec!(1));
+        eq!(eval("|2 - 5| + |1|").unwrap().value, dec!(4));
+        eq!(eval("def m; abs(-5 m) == 5 m").unwrap().value, dec!(1));
+        eq!(eval("abs(3) == 3").unwrap().value, dec!(1));
+        eq!(eval("def m; abs(-0.5 m)").unwrap().to_string(), "0.5 [m]");
+        eq!(eval("abs(0)").unwrap().value, dec!(0));
+    }
+
+    #[test]
+    fn logarithms() {
+        let value = |src| eval(src).unwrap().value;
+
+        assert!((value("ln(e)") - dec!(1)).abs() < dec!(0.000000000001));
+        eq!(value("log(100)"), dec!(2));
+        eq!(value("log(2, 8)"), dec!(3));
+        eq!(value("exp(0)"), dec!(1));
+        eq!(value("exp(ln(5))"), dec!(5));
+        eq!(value("def m; log(10 m / (1 m))"), dec!(1));
+
+        let err = eval("def m; ln(5 m)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 10..13);
+        eq!(
+            err.to_string(),
+            "ln expects a dimensionless argument, found: 5 [m]"
+        );
+        let err = eval("def s; log(2 s, 8)").unwrap_err();
+        eq!(err.span.into_range(), 11..14);
+
+        eq!(eval("ln(0)").unwrap_err().typ, ErrorType::DomainError);
+        eq!(eval("log(-1)").unwrap_err().typ, ErrorType::DomainError);
+        eq!(eval("exp(1000)").unwrap_err().typ, ErrorType::DomainError);
+        eq!(eval("log(1, 2, 3)").unwrap_err().typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn quoted_units() {
+        let show = |src| eval(src).unwrap().to_string();
+
+        eq!(show("def `US$`; 5 `US$` + 2 `US$`"), "7 [US$]");
+        eq!(
+            show("def `\u{b0}C`; x = 20 `\u{b0}C`; x * 2"),
+            "40 [\u{b0}C]"
+        );
+
+        let errs = parse("def m; `m").1;
+        eq!(errs[0].typ, ErrorType::CouldNotLex);
+        eq!(errs[0].span.into_range(), 7..9);
+        eq!(errs[0].to_string(), "unterminated '`' in unit name: `m");
+        eq!(parse("5 * `m").1[0].span.into_range(), 4..6);
+    }
+
+    #[test]
+    fn rounding_fns() {
+        let show = |src| eval(src).unwrap().to_string();
+
+        eq!(eval("def m; floor(3.7 m) == 3 m").unwrap().value, dec!(1));
+        eq!(show("def m; floor(-3.2 m)"), "-4 [m]");
+        eq!(show("floor(5)"), "5");
+
+        eq!(show("ceil(3.2)"), "4");
+        eq!(show("def s; ceil(-3.7 s)"), "-3 [s]");
+        eq!(show("ceil(-2)"), "-2");
+
+        eq!(show("round(3.6)"), "4");
+        eq!(show("round(2.5)"), "2");
+        eq!(show("round(3.5)"), "4");
+        eq!(show("def m; round(-2.5 m)"), "-2 [m]");
+        eq!(show("round(7)"), "7");
+
+        eq!(show("trunc(3.9)"), "3");
+        eq!(show("def m; trunc(-3.9 m)"), "-3 [m]");
+        eq!(show("trunc(4)"), "4");
+
+        let err = eval("floor(1, 2)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+    }
+
+    #[test]
+    fn pow_assign() {
+        eq!(eval("x = 3; x ^= 2; x").unwrap().value, dec!(9));
+        eq!(
+            eval("def m; x = 2 m; x ^= 3").unwrap().to_string(),
+            "8 [m^3]"
+        );
+        eq!(eval("y ^= 2").unwrap_err().typ, ErrorType::UndefinedIdent);
+    }
+
+    #[test]
+    fn trig() {
+        eq!(eval("def deg; sin(90 deg)").unwrap().value, dec!(1));
+        eq!(eval("def deg; cos(180 deg)").unwrap().value, dec!(-1));
+        eq!(eval("def deg; tan(45 deg)").unwrap().value, dec!(1));
+        eq!(eval("def rad; cos(0 rad)").unwrap().value, dec!(1));
+        eq!(eval("sin(0)").unwrap().value, dec!(0));
+        eq!(eval("sin(3)").unwrap().value, dec!(0.141120008059867));
+
+        let err = eval("def m; sin(5 m)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.to_string(), "sin expects an angle, found: 5 [m]");
+    }
+
+    #[test]
+    fn mismatch_points_at_unit() {
+        let err = eval("def kg; def m; 3 kg + 3 m").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 24..25);
+
+        let err = eval("def m; def s; 2 m * s - 4 m").unwrap_err();
+        eq!(err.span.into_range(), 20..21);
+
+        // the scalar side has no unit, so the other side is reported
+        let err = eval("def m; 3 m + 1").unwrap_err();
+        eq!(err.span.into_range(), 9..10);
+    }
+
+    #[test]
+    fn unit_exponent() {
+        eq!(eval("def m; 5 m^2").unwrap().to_string(), "5 [m^2]");
+        eq!(eval("def m; m^2 * m").unwrap().to_string(), "1 [m^3]");
+        eq!(eval("def m; m^2 / m").unwrap().to_string(), "1 [m]");
+        eq!(eval("def s; 2 s^-1").unwrap().to_string(), "2 [1/s]");
+        eq!(eval("def m; m^(1/2)").unwrap().to_string(), "1 [m^0.5]");
+        eq!(eval("def m; 4 m^(1/2)").unwrap().to_string(), "4 [m^0.5]");
+        eq!(eval("def s; s^(-2)").unwrap().to_string(), "1 [1/s^2]");
+        eq!(eval("def s; 3 s^(-2) * s^2").unwrap().to_string(), "3");
+
+        let err = eval("def m; def s; m^(2 s)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.span.into_range(), 14..20);
+        eq!(
+            err.to_string(),
+            "exponent can't contain a unit, found: 2 [s]"
+        );
+    }
+
+    #[test]
+    fn accessors() {
+        let res = eval("def m; def s; 3 m / 2 s").unwrap();
+        eq!(res.value(), dec!(1.5));
+        eq!(res.unit().to_string(), "[m/s]");
+
+        let (value, unit) = res.into_parts();
+        eq!(value, dec!(1.5));
+        eq!(unit.to_string(), "[m/s]");
+    }
+
+    #[test]
+    fn def_fn() {
+        eq!(
+            eval("def f(x, y) { x + y }; f(1, 2)").unwrap().value,
+            dec!(3)
+        );
+        eq!(
+            eval("def m; def area(w, h) { w * h }; area(2 m, 3 m) == 6 m^2")
+                .unwrap()
+                .value,
+            dec!(1)
+        );
+        eq!(
+            eval("x = 5; def f(x) { x * 2 }; f(1) + x").unwrap().value,
+            dec!(7)
+        );
+
+        let err = eval("def f(x, y) { x + y }; f(1)").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.to_string(), "f expects 2 argument(s), found 1");
+    }
+
+    #[test]
+    fn call_depth() {
+        let err = eval("def f(x) { f(x) }; f(1)").unwrap_err();
+        eq!(err.typ, ErrorType::TooDeep);
+        eq!(err.span.into_range(), 11..15);
+
+        let src = format!(
+            "def f(n) {{ if n > 0 {{ f(n - 1) }} else {{ 0 }} }}; f({})",
+            MAX_CALL_DEPTH - 1
+        );
+        eq!(eval(&src).unwrap().value, dec!(0));
+        let src = src.replace(
+            &format!("f({})", MAX_CALL_DEPTH - 1),
+            &format!("f({})", MAX_CALL_DEPTH),
+        );
+        eq!(eval(&src).unwrap_err().typ, ErrorType::TooDeep);
+    }
+
+    #[test]
+    fn overflow() {
+        let err = eval("x = 79228162514264337593543950335; x * 2").unwrap_err();
+        eq!(err.typ, ErrorType::Overflow);
+        eq!(err.span.into_range(), 35..40);
+    }
+
+    #[test]
+    fn convert() {
+        let res = eval("def m; 1000 m : km").unwrap();
+        eq!(res.value, dec!(1));
+        eq!(res.unit.to_string(), "[km]");
+
+        let res = eval("def m; def s; 2 m + 3 m / 1 s * 1 s : mm").unwrap();
+        eq!(res.value, dec!(5000));
+
+        let res = eval("def km; def s; 36 km / 1 s : m / s").unwrap();
+        eq!(res.value, dec!(36000));
+
+        let err = eval("def m; def s; 5 m : s").unwrap_err();
+        eq!(err.typ, ErrorType::TypeError);
+        eq!(err.to_string(), "can't convert [m] to [s]");
+    }
+
+    #[test]
+    fn reciprocal() {
+        let show = |src| eval(src).unwrap().to_string();
+
+        eq!(show("def m; 1 / (2 m)"), "0.5 [1/m]");
+        eq!(show("def m; def s; 1 / (4 m / s)"), "0.25 [s/m]");
+        eq!(show("def m; x = 1; x /= 2 m"), "0.5 [1/m]");
+        eq!(
+            eval("def m; 1 / (0 m)").unwrap_err().typ,
+            ErrorType::ZeroDivision
+        );
+        eq!(eval("1 / 3").unwrap().value, dec!(1) / dec!(3));
+    }
+
+    #[test]
+    fn zero_division() {
+        let err = eval("5 / (2 - 2)").unwrap_err();
+        eq!(err.typ, ErrorType::ZeroDivision);
+        eq!(err.to_string(), "division by zero");
+        eq!(err.span.into_range(), 4..11);
+
+        let err = eval("x = 0; 1 + 4 / x").unwrap_err();
+        eq!(err.span.into_range(), 15..16);
+
+        let err = eval("x = 1; x /= ((1 - 1))").unwrap_err();
+        eq!(err.typ, ErrorType::ZeroDivision);
+        eq!(err.span.into_range(), 12..21);
+
+        let err = eval("7 mod (3 * 0)").unwrap_err();
+        eq!(err.typ, ErrorType::ZeroDivision);
+        eq!(err.span.into_range(), 6..13);
+    }
+}
@@ -1,8 +1,10 @@
+mod check;
 mod error;
 mod eval;
 mod morph;
 mod parser;
 mod types;
+mod visit;
 
 const SRC: &str = r"
 def m
@@ -17,5 +19,9 @@ def s
 ";
 
 fn main() {
-    morph::run("<STDIN>", SRC);
+    // with --free-units an unknown name is a unit of its own instead of an error
+    let context = eval::Context::new();
+    context.set_strict_units(!std::env::args().any(|arg| arg == "--free-units"));
+
+    morph::run("<STDIN>", SRC, context);
 }
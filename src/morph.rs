@@ -1,23 +1,238 @@
+use std::ops::Range;
+
 use crate::error::*;
 use crate::eval::*;
 use crate::types::*;
 
 use logos::Logos;
 
-use chumsky::{input::Stream, prelude::Input, Parser};
+use chumsky::{input::Stream, prelude::Input, span::SimpleSpan, Parser};
+
+// the evaluator recurses once per level of the tree, an unoptimized build
+// takes about 20 KiB of stack for each
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexDiagnostic<'a> {
+    pub slice: &'a str,
+    pub span: Range<usize>,
+    pub err: LexError,
+}
+
+#[allow(dead_code)]
+pub fn lex_all(src: &str) -> (Vec<(Token<'_>, Range<usize>)>, Vec<LexDiagnostic<'_>>) {
+    let mut tokens = vec![];
+    let mut diagnostics = vec![];
+
+    for (tok, span) in Token::lexer(src).spanned() {
+        match tok {
+            Ok(tok) => tokens.push((tok, span)),
+            Err(err) => diagnostics.push(LexDiagnostic {
+                slice: &src[span.clone()],
+                span,
+                err,
+            }),
+        }
+    }
+
+    (tokens, diagnostics)
+}
+
+// tokens with their spans and one token of lookahead, lexer errors are passed
+// on as LexErr tokens like the parser expects them
+pub struct TokenStream<'a> {
+    src: &'a str,
+    lexer: logos::SpannedIter<'a, Token<'a>>,
+    peeked: Option<Option<(Token<'a>, Range<usize>)>>,
+    coalesce_newlines: bool,
+}
+
+impl<'a> TokenStream<'a> {
+    #[allow(dead_code)]
+    pub fn new(src: &'a str) -> Self {
+        Self::with_options(src, LexOptions::default())
+    }
+
+    pub fn with_options(src: &'a str, options: LexOptions) -> Self {
+        Self {
+            src,
+            lexer: Token::lexer_with_extras(src, options).spanned(),
+            peeked: None,
+            coalesce_newlines: false,
+        }
+    }
+
+    // yield a run of separators as its first one
+    #[allow(dead_code)]
+    pub fn coalesce_newlines(mut self, coalesce: bool) -> Self {
+        self.coalesce_newlines = coalesce;
+        self
+    }
+
+    pub fn peek(&mut self) -> Option<&(Token<'a>, Range<usize>)> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex());
+        }
+
+        self.peeked.as_ref().and_then(Option::as_ref)
+    }
+
+    fn lex(&mut self) -> Option<(Token<'a>, Range<usize>)> {
+        self.lexer.next().map(|(tok, span)| match tok {
+            Ok(tok) => (tok, span),
+            Err(err) => (Token::LexErr((&self.src[span.clone()], err)), span),
+        })
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = (Token<'a>, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.lex(),
+        };
+
+        if self.coalesce_newlines && matches!(next, Some((Token::NL, _))) {
+            while let Some((Token::NL, _)) = self.peek() {
+                self.peeked = None;
+            }
+        }
+
+        next
+    }
+}
+
+// the parser recurses for every group, every prefix operator and every '|',
+// '^', '?' or assignment in a statement. Input nested deep enough would
+// overflow the stack before any error is reported
+fn too_deep(tokens: &[(Token<'_>, SimpleSpan)], max_depth: usize) -> Option<SimpleSpan> {
+    let mut groups = 0usize;
+    let mut chain = 0usize;
+    let mut prev: Option<&Token> = None;
+
+    for (tok, span) in tokens {
+        // a '+' or '-' that doesn't follow an operand is a prefix
+        let prefix = !matches!(
+            prev,
+            Some(
+                Token::Num(_)
+                    | Token::Unit(_)
+                    | Token::RParen
+                    | Token::RCurly
+                    | Token::RBracket
+                    | Token::Percent
+                    | Token::Bar
+            )
+        );
+        prev = Some(tok);
+
+        match tok {
+            Token::LParen | Token::LCurly | Token::LBracket => groups += 1,
+            Token::RParen | Token::RCurly | Token::RBracket => groups = groups.saturating_sub(1),
+            Token::Bar | Token::Pow | Token::Not | Token::Percent | Token::Question => chain += 1,
+            Token::Assign
+            | Token::AddAssign
+            | Token::SubAssign
+            | Token::MulAssign
+            | Token::DivAssign
+            | Token::PowAssign => chain += 1,
+            Token::Add | Token::Sub if prefix => chain += 1,
+            Token::NL => chain = 0,
+            _ => continue,
+        }
+
+        if groups + chain > max_depth {
+            return Some(*span);
+        }
+    }
+
+    None
+}
+
+pub fn parse(src: &str) -> ParseResult<'_> {
+    parse_with(src, LexOptions::default(), MAX_DEPTH)
+}
+
+#[allow(dead_code)]
+pub fn parse_with_depth(src: &str, max_depth: usize) -> ParseResult<'_> {
+    parse_with(src, LexOptions::default(), max_depth)
+}
+
+// like parse with the lexer set up by `options`, e.g. for a decimal comma
+pub fn parse_with(src: &str, options: LexOptions, max_depth: usize) -> ParseResult<'_> {
+    let tokens: Vec<(Token, SimpleSpan)> = TokenStream::with_options(src, options)
+        .map(|(tok, span)| (tok, span.into()))
+        .collect();
+
+    if let Some(span) = too_deep(&tokens, max_depth) {
+        let err = MorphError::custom(
+            span,
+            format!("input is nested deeper than {} levels", max_depth),
+            ErrorType::TooDeep,
+        );
+        return (None, vec![err]);
+    }
+
+    let token_stream = Stream::from_iter(tokens).spanned((src.len()..src.len()).into());
+    let (root, errors) = Node::parser().parse(token_stream).into_output_errors();
+
+    // a flat chain like 1 + 1 + ... parses without recursing but still builds a
+    // tree the evaluator recurses through, so the statements are bounded too
+    let deepest = root
+        .as_ref()
+        .and_then(|root| root.levels().find(|(level, _)| *level > max_depth));
+    if let Some((_, node)) = deepest {
+        let err = MorphError::custom(
+            node.span.clone(),
+            format!("input is nested deeper than {} levels", max_depth),
+            ErrorType::TooDeep,
+        );
+        return (None, vec![err]);
+    }
+
+    (root, errors)
+}
+
+// the parser recovers at statement boundaries, failed statements become ParseError nodes
+pub fn parse_program(src: &str) -> (Vec<Node<'_>>, Vec<MorphError<'_>>) {
+    parse_program_with(src, LexOptions::default())
+}
+
+#[allow(dead_code)]
+pub fn parse_program_with(src: &str, options: LexOptions) -> (Vec<Node<'_>>, Vec<MorphError<'_>>) {
+    let (root, errors) = parse_with(src, options, MAX_DEPTH);
+
+    let stmts = match root.map(|root| root.typ) {
+        Some(NodeType::Scope(stmts)) => stmts,
+        Some(_) => unreachable!("the parser always produces a root scope"),
+        None => vec![],
+    };
+
+    (stmts, errors)
+}
+
+// evaluates one line against a context that outlives it, as a REPL needs,
+// the statements run directly in `cntxt` so assignments and definitions stay
+#[allow(dead_code)]
+pub fn evaluate_line<'a>(src: &'a str, cntxt: &Context<'a>) -> RuntimeResult<'a> {
+    let (stmts, mut errors) = parse_program(src);
 
-pub fn parse(src: &str) -> ParseResult {
-    let token_iter = Token::lexer(src).spanned().map(|(tok, span)| match tok {
-        Ok(tok) => (tok, span.into()),
-        Err(()) => (Token::LexErr(&src[span.clone()]), span.into()),
-    });
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
 
-    let token_stream = Stream::from_iter(token_iter).spanned((src.len()..src.len()).into());
+    let mut res = Quantity::zero(src.len()..src.len());
+    for stmt in stmts {
+        res = stmt.eval(cntxt.clone())?;
+    }
 
-    Node::parser().parse(token_stream).into_output_errors()
+    Ok(res)
 }
 
-pub fn run(file_name: &str, src: &str) {
+// evaluates `src` in `context` and reports the result or the errors
+pub fn run<'a>(file_name: &str, src: &'a str, context: Context<'a>) {
     let (root, errors) = parse(src);
 
     for err in &errors {
@@ -29,9 +244,8 @@ pub fn run(file_name: &str, src: &str) {
     }
 
     if let Some(ast) = root {
-        let context = Context::new();
-        match ast.eval(context) {
-            Ok(res) => println!("{}", res),
+        match ast.eval_value(context.clone()) {
+            Ok(res) => println!("{}", context.display(&res)),
             Err(err) => err.report(file_name, src),
         }
         // if let Ok(res) = ast.clone().eval(context) {
@@ -51,10 +265,7 @@ pub mod test_utils {
 
     macro_rules! bod {
         ($($x:expr),*) => {{
-            #[allow(unused_mut)]
-            let mut vec = Vec::new();
-            $(vec.push($x);)*
-            crate::types::NodeType::Scope(vec)
+            crate::types::NodeType::Scope(vec![$($x),*])
         }};
     }
 
@@ -68,11 +279,11 @@ pub mod test_utils {
     pub(crate) use eq;
     use rust_decimal::Decimal;
 
-    pub fn u(name: &str) -> Node {
+    pub fn u(name: &str) -> Node<'_> {
         Node::new(NodeType::Unit(name), 0..0)
     }
 
-    pub fn d(name: &str) -> Node {
+    pub fn d(name: &str) -> Node<'_> {
         Node::new(NodeType::Def(name), 0..0)
     }
 
@@ -80,6 +291,10 @@ pub mod test_utils {
         Node::new(NodeType::Num(val.into()), 0..0)
     }
 
+    pub fn call<'a>(name: &'a str, args: Vec<Node<'a>>) -> Node<'a> {
+        Node::new(NodeType::FnCall { name, args }, 0..0)
+    }
+
     type Reason<'a> = chumsky::error::RichReason<'a, Token<'a>, &'a str>;
     type Pattern<'a> = chumsky::error::RichPattern<'a, Token<'a>, &'a str>;
 
@@ -103,11 +318,249 @@ pub mod test_utils {
         false
     }
 
-    pub fn parse(code: &str) -> morph::ParseResult {
+    pub fn parse(code: &str) -> morph::ParseResult<'_> {
         morph::parse(code)
     }
 
-    pub fn nodes(code: &str) -> NodeType {
+    pub fn nodes(code: &str) -> NodeType<'_> {
         morph::parse(code).0.unwrap().into()
     }
+
+    pub fn eval(code: &str) -> crate::error::RuntimeResult<'_> {
+        let (root, errors) = morph::parse(code);
+        assert!(
+            errors.is_empty(),
+            "could not parse {:?}: {:?}",
+            code,
+            errors
+        );
+        root.unwrap().eval(crate::eval::Context::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn token_stream() {
+        let mut stream = TokenStream::new("1 +\n\n\nx $");
+
+        assert_eq!(stream.peek(), Some(&(Token::Num(dec!(1)), 0..1)));
+        assert_eq!(stream.peek(), Some(&(Token::Num(dec!(1)), 0..1)));
+        assert_eq!(stream.next(), Some((Token::Num(dec!(1)), 0..1)));
+        assert_eq!(stream.next(), Some((Token::Add, 2..3)));
+        assert_eq!(stream.filter(|(tok, _)| *tok == Token::NL).count(), 3);
+
+        let toks: Vec<_> = TokenStream::new("1 +\n\n\nx $")
+            .coalesce_newlines(true)
+            .map(|(tok, _)| tok)
+            .collect();
+        assert_eq!(toks.len(), 5);
+        assert_eq!(toks[2], Token::NL);
+        assert_eq!(toks[4], Token::LexErr(("$", LexError::UnknownChar)));
+
+        let options = LexOptions {
+            decimal_comma: true,
+        };
+        let toks: Vec<_> = TokenStream::with_options("0,5", options).collect();
+        assert_eq!(toks, vec![(Token::Num(dec!(0.5)), 0..3)]);
+
+        let mut stream = TokenStream::new("a;\n").coalesce_newlines(true);
+        assert_eq!(stream.nth(1), Some((Token::NL, 1..2)));
+        assert_eq!(stream.peek(), None);
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+
+        let src = nested(10_000);
+        let (root, errors) = parse(&src);
+        assert!(root.is_none());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].typ, ErrorType::TooDeep);
+        assert_eq!(errors[0].span.into_range(), MAX_DEPTH..MAX_DEPTH + 1);
+
+        let src = nested(MAX_DEPTH - 1);
+        let (root, errors) = parse(&src);
+        assert!(errors.is_empty());
+        assert_eq!(root.unwrap().eval(Context::new()).unwrap().value, dec!(1));
+
+        let src = "2".to_owned() + &"^2".repeat(10_000);
+        assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+
+        // prefix and postfix runs nest the tree as deeply as groups do
+        let src = "-".repeat(1000) + "1";
+        assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+        let src = "1".to_owned() + &"%".repeat(100_000);
+        assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+        let src = "!-".repeat(1000) + "1";
+        assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+        assert!(parse(&"1 - 1 + 1 - 1".repeat(10)).1.is_empty());
+
+        // flat chains build trees as deep as they are long
+        for (first, op) in [("1", "+1"), ("1", "*1"), ("1", " == 1"), ("1 m", " : m")] {
+            let src = first.to_owned() + &op.repeat(4000);
+            assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+            let src = first.to_owned() + &op.repeat(400);
+            assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+        }
+        let src = "x = ".repeat(1000) + "1";
+        assert_eq!(parse(&src).1[0].typ, ErrorType::TooDeep);
+
+        let src = "1".to_owned() + &"+1".repeat(MAX_DEPTH - 1);
+        let (root, errors) = parse(&src);
+        assert!(errors.is_empty());
+        let value = root.unwrap().eval(Context::new()).unwrap().value;
+        assert_eq!(value, Decimal::from(MAX_DEPTH));
+        let src = "1".to_owned() + &"+1".repeat(MAX_DEPTH);
+        let (root, errors) = parse(&src);
+        assert!(root.is_none());
+        assert_eq!(errors[0].span.into_range(), 0..1);
+        assert_eq!(parse_with_depth("((1))", 1).1[0].span.into_range(), 1..2);
+        assert!(parse_with_depth("(1); (2)", 1).1.is_empty());
+    }
+
+    #[test]
+    fn lex_options() {
+        let options = LexOptions {
+            decimal_comma: true,
+        };
+
+        let (root, errors) = parse_with("0,5 + 1", options, MAX_DEPTH);
+        assert!(errors.is_empty());
+        assert_eq!(root.unwrap().eval(Context::new()).unwrap().value, dec!(1.5));
+
+        let (stmts, errors) = parse_program_with("x = 2,5\nx * 2", options);
+        assert!(errors.is_empty());
+        assert_eq!(stmts[1].to_string(), "(x * 2)");
+        assert_eq!(
+            parse_program_with("max(1, 2)", options).1[0].found(),
+            Some(&Token::LexErr((",", LexError::CommaDelimiter)))
+        );
+
+        // the default still reads ',' as a delimiter
+        assert!(parse("max(1, 2)").1.is_empty());
+    }
+
+    #[test]
+    fn program_recovery() {
+        let (stmts, errors) = parse_program("x = 1\n3 ) 4\nx * 2");
+
+        assert_eq!(stmts.len(), 3);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts[1].typ, NodeType::ParseError);
+        assert_eq!(stmts[1].span, 6..11);
+        assert_eq!(stmts[2].to_string(), "(x * 2)");
+
+        let valid: Vec<_> = stmts
+            .into_iter()
+            .filter(|stmt| stmt.typ != NodeType::ParseError)
+            .collect();
+        assert_eq!(valid.len(), 2);
+
+        let context = Context::new();
+        for stmt in valid {
+            stmt.eval(context.clone()).unwrap();
+        }
+    }
+
+    #[test]
+    fn repl_lines() {
+        let context = Context::new();
+        let line = |src| evaluate_line(src, &context).map(|res| res.to_string());
+
+        assert_eq!(line("x = 2").unwrap(), "2");
+        assert_eq!(line("x + 3").unwrap(), "5");
+        assert_eq!(line("def m; y = x * m").unwrap(), "2 [m]");
+        assert_eq!(line("y *= 2; y").unwrap(), "4 [m]");
+        assert_eq!(line("").unwrap(), "0");
+
+        assert_eq!(line("x +").unwrap_err().typ, ErrorType::ExpectedOperand);
+        assert_eq!(line("z").unwrap_err().typ, ErrorType::UnknownUnit);
+        assert_eq!(line("x").unwrap(), "2");
+        assert_eq!(line("y = 3\r\ny * x\r\n").unwrap(), "6");
+    }
+
+    #[test]
+    fn display_precision() {
+        let context = Context::new();
+        context.set_display_precision(Some(2));
+        let line = |src| {
+            let res = evaluate_line(src, &context).unwrap();
+            (context.display(&Value::Quantity(res.clone())), res.value)
+        };
+
+        assert_eq!(line("x = 10/3").0, "3.33");
+
+        let (shown, value) = line("x * 3");
+        assert_eq!(shown, "10");
+        assert!(value > dec!(9.99));
+
+        assert_eq!(line("def m; 2/3 * m").0, "0.67 [m]");
+        assert_eq!(
+            context.display(&Value::List(vec![Quantity::num(dec!(1.005), 0..0)])),
+            "[1]"
+        );
+
+        context.set_display_precision(None);
+        assert_eq!(line("x").0, "3.3333333333333333333333333333");
+    }
+
+    #[test]
+    fn error_snippet() {
+        let src = "def m;\nx = 5 metre + 1 m";
+        let err = evaluate_line(src, &Context::new()).unwrap_err();
+        assert_eq!(
+            err.snippet(src),
+            "error[12]: use of undeclared unit/variable 'metre'\n\
+             2 | x = 5 metre + 1 m\n  \
+             |       ^^^^^"
+        );
+
+        let src = "2 *";
+        let err = evaluate_line(src, &Context::new()).unwrap_err();
+        assert!(err.snippet(src).ends_with("1 | 2 *\n  |   ^"));
+
+        // columns count characters, not bytes
+        let err = MorphError::custom(5..10, "oops", ErrorType::Other);
+        assert_eq!(
+            err.snippet("\u{b5} + metre"),
+            "error[00]: oops\n1 | \u{b5} + metre\n  |     ^^^^^"
+        );
+    }
+
+    #[test]
+    fn lex_errors() {
+        let (tokens, diagnostics) = lex_all("1 @ m + @2");
+
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Num(dec!(1)), 0..1),
+                (Token::Unit(UnitIdent::plain("m")), 4..5),
+                (Token::Add, 6..7),
+                (Token::Num(dec!(2)), 9..10),
+            ]
+        );
+        assert_eq!(
+            diagnostics,
+            vec![
+                LexDiagnostic {
+                    slice: "@",
+                    span: 2..3,
+                    err: LexError::UnknownChar,
+                },
+                LexDiagnostic {
+                    slice: "@",
+                    span: 8..9,
+                    err: LexError::UnknownChar,
+                },
+            ]
+        );
+    }
 }
@@ -3,7 +3,11 @@ use std::ops;
 use crate::error::*;
 use crate::types::*;
 
-use chumsky::{input::ValueInput, prelude::*, primitive};
+use chumsky::{
+    input::{Emitter, ValueInput},
+    prelude::*,
+    primitive,
+};
 
 macro_rules! merge_expected {
     ($err: ident ::<$I: ty>, $exp_tok: expr) => {{
@@ -40,7 +44,7 @@ macro_rules! parse {
     };
 
     (unit) => {
-        select!(Token::Unit(x) => NodeType::Unit(x)).map_with_span(Node::new)
+        select!(Token::Unit(x) => NodeType::Unit(x.name)).map_with_span(Node::new)
     };
 
     (num * unit) => {
@@ -48,19 +52,255 @@ macro_rules! parse {
     };
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+const BINARY_OPS: [Token<'static>; 22] = [
+    Token::Equal,
+    Token::NeEqual,
+    Token::GreaterEqual,
+    Token::LesserEqual,
+    Token::Greater,
+    Token::Lesser,
+    Token::Assign,
+    Token::AddAssign,
+    Token::SubAssign,
+    Token::MulAssign,
+    Token::DivAssign,
+    Token::PowAssign,
+    Token::Add,
+    Token::Sub,
+    Token::Mul,
+    Token::Div,
+    Token::Mod,
+    Token::Pow,
+    Token::Bar,
+    Token::Amp,
+    Token::Shl,
+    Token::Shr,
+];
+
+// prefix '-', '+' and '!' bind looser than '^' but tighter than '*', a postfix
+// '%' binds tighter than any operator
+pub const PREFIX_PRECEDENCE: u8 = 8;
+pub const POSTFIX_PRECEDENCE: u8 = 10;
+
+// binding power of the binary operators, higher binds tighter. The parser has
+// one layer per level
+pub fn precedence(tok: &Token) -> Option<(u8, Assoc)> {
+    use Token::*;
+
+    match tok {
+        Equal | NeEqual | GreaterEqual | LesserEqual | Greater | Lesser => Some((1, Assoc::Left)),
+        Assign | AddAssign | SubAssign | MulAssign | DivAssign | PowAssign => {
+            Some((2, Assoc::Right))
+        }
+        Bar => Some((3, Assoc::Left)),
+        Amp => Some((4, Assoc::Left)),
+        Shl | Shr => Some((5, Assoc::Left)),
+        Add | Sub => Some((6, Assoc::Left)),
+        Mul | Div | Mod => Some((7, Assoc::Left)),
+        Pow => Some((9, Assoc::Right)),
+        _ => None,
+    }
+}
+
+// the operator tokens of one level of the precedence table
+fn binop_at<'a, I>(level: u8) -> Boxed<'a, 'a, I, Token<'a>, extra::Err<MorphError<'a>>>
+where
+    I: ValueInput<'a, Token = Token<'a>, Span = SimpleSpan>,
+{
+    let ops = BINARY_OPS
+        .iter()
+        .filter(|tok| precedence(tok).is_some_and(|(p, _)| p == level))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    one_of(ops).boxed()
+}
+
+// an operator and its right operand. If the statement ends right after the
+// operator, the error points at the operator and the operand becomes an error node
+fn binop_then<'a, I>(
+    level: u8,
+    operand: Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>,
+) -> Boxed<'a, 'a, I, (Token<'a>, Node<'a>), extra::Err<MorphError<'a>>>
+where
+    I: ValueInput<'a, Token = Token<'a>, Span = SimpleSpan>,
+{
+    let missing = choice((
+        end(),
+        one_of([Token::NL, Token::RCurly, Token::RParen, Token::Comma]).ignored(),
+    ))
+    .rewind();
+
+    // a '|' only continues an or if no sign or other bar follows, otherwise it
+    // closes an abs, so |a| - |b| still parses as written. A missing operand
+    // isn't reported either, the bar is left for the abs to close
+    let rhs = match precedence(&Token::Bar) {
+        Some((bar, _)) if bar == level => none_of([Token::Sub, Token::Add, Token::Not, Token::Bar])
+            .rewind()
+            .ignore_then(operand)
+            .map(Some)
+            .boxed(),
+        _ => choice((operand.map(Some), missing.to(None))).boxed(),
+    };
+
+    binop_at(level)
+        .map_with_span(|op, span: SimpleSpan| (op, span))
+        .then(rhs)
+        .validate(|((op, span), rhs), _, emit| {
+            let rhs = rhs.unwrap_or_else(|| {
+                emit.emit(MorphError::custom(
+                    span,
+                    format!("expected an operand after '{}'", op),
+                    ErrorType::ExpectedOperand,
+                ));
+                Node::err(span)
+            });
+            (op, rhs)
+        })
+        .boxed()
+}
+
+// the levels of the precedence table with their associativity, tightest first
+fn levels() -> Vec<(u8, Assoc)> {
+    let mut levels: Vec<_> = BINARY_OPS.iter().filter_map(precedence).collect();
+    levels.sort_unstable_by(|(l, _), (r, _)| r.cmp(l));
+    levels.dedup();
+    levels
+}
+
+// one layer of the precedence table, a right associative level nests into its
+// right operand, `x = y = 2` sets both. `rhs` wraps the parser of a right operand
+fn binop_level<'a, I>(
+    level: u8,
+    assoc: Assoc,
+    operand: Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>,
+    rhs: impl Fn(
+        Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>,
+    ) -> Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>,
+) -> Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>
+where
+    I: ValueInput<'a, Token = Token<'a>, Span = SimpleSpan>,
+{
+    let join = |op, lhs: Node<'a>, rhs: Node<'a>, emit: &mut Emitter<MorphError<'a>>| {
+        let span = merge_span(&lhs.span, &rhs.span);
+        combine(op, lhs, rhs).unwrap_or_else(|err| {
+            emit.emit(err);
+            Node::err(span)
+        })
+    };
+
+    match assoc {
+        Assoc::Left => operand
+            .clone()
+            .then(
+                binop_then(level, rhs(operand))
+                    .repeated()
+                    .collect::<Vec<_>>(),
+            )
+            .validate(move |(first, rest), _, emit| {
+                rest.into_iter()
+                    .fold(first, |lhs, (op, rhs)| join(op, lhs, rhs, emit))
+            })
+            .boxed(),
+        Assoc::Right => recursive(|this| {
+            operand
+                .then(binop_then(level, rhs(this.boxed())).or_not())
+                .validate(move |(lhs, rest), _, emit| match rest {
+                    Some((op, rhs)) => join(op, lhs, rhs, emit),
+                    None => lhs,
+                })
+        })
+        .boxed(),
+    }
+}
+
+// a target that isn't an identifier is reported without losing the statement
+fn combine<'a>(op: Token<'a>, lhs: Node<'a>, rhs: Node<'a>) -> Result<Node<'a>, MorphError<'a>> {
+    use Token::*;
+
+    match op {
+        Assign | AddAssign | SubAssign | MulAssign | DivAssign | PowAssign => {
+            let mut target = lhs;
+            assign(op, &mut target, rhs)?;
+            Ok(target)
+        }
+        _ => Ok(binary(op, lhs, rhs)),
+    }
+}
+
+fn binary<'a>(op: Token<'a>, lhs: Node<'a>, rhs: Node<'a>) -> Node<'a> {
+    use NodeType::*;
+
+    let span = merge_span(&lhs.span, &rhs.span);
+    let (lhs, rhs) = (Box::new(lhs), Box::new(rhs));
+
+    let typ = match op {
+        Token::Equal => Equal(lhs, rhs),
+        Token::NeEqual => NeEqual(lhs, rhs),
+        Token::GreaterEqual => GreaterEqual(lhs, rhs),
+        Token::LesserEqual => LesserEqual(lhs, rhs),
+        Token::Greater => Greater(lhs, rhs),
+        Token::Lesser => Lesser(lhs, rhs),
+        Token::Add => Add(lhs, rhs),
+        Token::Sub => Sub(lhs, rhs),
+        Token::Mul => Mul(lhs, rhs),
+        Token::Div => Div(lhs, rhs),
+        Token::Mod => Mod(lhs, rhs),
+        Token::Pow => Pow(lhs, rhs),
+        Token::Amp => BitAnd(lhs, rhs),
+        Token::Bar => BitOr(lhs, rhs),
+        Token::Shl => Shl(lhs, rhs),
+        Token::Shr => Shr(lhs, rhs),
+        _ => unreachable!("{} is not a binary operator", op),
+    };
+
+    Node::new(typ, span)
+}
+
+fn assign<'a>(op: Token<'a>, lhs: &mut Node<'a>, rhs: Node<'a>) -> Result<(), MorphError<'a>> {
+    let ctor: AssignCtor<'a> = match op {
+        Token::Assign => NodeType::Assign,
+        Token::AddAssign => NodeType::AddAssign,
+        Token::SubAssign => NodeType::SubAssign,
+        Token::MulAssign => NodeType::MulAssign,
+        Token::DivAssign => NodeType::DivAssign,
+        Token::PowAssign => NodeType::PowAssign,
+        _ => unreachable!("{} is not an assignment", op),
+    };
+
+    lhs.assign_with(ctor, rhs)
+}
+
+// wraps an exponent in a negation if it was preceded by a '-' at `neg`
+fn negate(neg: Option<SimpleSpan>, exp: Node<'_>) -> Node<'_> {
+    match neg {
+        Some(span) => {
+            let span = merge_span(&span.into_range(), &exp.span);
+            Node::new(NodeType::UnrySub(exp.into()), span)
+        }
+        None => exp,
+    }
+}
+
 impl<'a> Node<'a> {
     pub fn syntax_err<I>() -> Boxed<'a, 'a, I, Node<'a>, extra::Err<MorphError<'a>>>
     where
         I: ValueInput<'a, Token = Token<'a>, Span = SimpleSpan>,
     {
-        primitive::select::<'_, _, I, &str, extra::Err<MorphError<'a>>>(|x, _| match x {
+        primitive::select::<'_, _, I, _, extra::Err<MorphError<'a>>>(|x, _| match x {
             Token::LexErr(err) => Some(err),
             _ => None,
         })
-        .validate(|x, span, emit| {
+        .validate(|(slice, err), span, emit| {
             emit.emit(MorphError::custom(
                 span,
-                format!("unsupported character: {}", x),
+                format!("{}: {}", err, slice),
                 ErrorType::CouldNotLex,
             ))
         })
@@ -77,125 +317,266 @@ impl<'a> Node<'a> {
         let expr = recursive(|expr| {
             let def = just(Token::Def)
                 .ignore_then(parse!(unit))
-                .map_with_span(|x: Node, span: SimpleSpan| cast_enum!(x.typ => (NodeType::Unit(name)) {return Node::new(NodeType::Def(name), span)}))
+                .map_with_span(|x: Node, span: SimpleSpan| cast_enum!(x.typ => (NodeType::Unit(name)) {Node::new(NodeType::Def(name), span)}))
                 .map_err(|err: MorphError| merge_expected!(err::<I>, [Token::UNIT]));
 
+            let def_fn = just(Token::Def)
+                .ignore_then(select!(Token::Unit(x) => x.name))
+                .then(
+                    select!(Token::Unit(x) => x.name)
+                        .separated_by(just(Token::Comma))
+                        .collect::<Vec<_>>()
+                        .delimited_by(just(Token::LParen), just(Token::RParen)),
+                )
+                .then(
+                    scope_parser
+                        .clone()
+                        .delimited_by(just(Token::LCurly), just(Token::RCurly)),
+                )
+                .map_with_span(|((name, params), body), span: SimpleSpan| {
+                    Node::new(
+                        NodeType::DefFn {
+                            name,
+                            params,
+                            body: body.into(),
+                        },
+                        span,
+                    )
+                })
+                .boxed();
+
+            let fn_call = select!(Token::Unit(x) => x.name)
+                .then(
+                    expr.clone()
+                        .separated_by(just(Token::Comma))
+                        .collect::<Vec<_>>()
+                        .delimited_by(just(Token::LParen), just(Token::RParen)),
+                )
+                .map_with_span(|(name, args), span: SimpleSpan| {
+                    Node::new(NodeType::FnCall { name, args }, span)
+                })
+                .boxed();
+
+            let list = expr
+                .clone()
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(just(Token::LBracket), just(Token::RBracket))
+                .map_with_span(|items, span: SimpleSpan| Node::new(NodeType::List(items), span))
+                .boxed();
+
+            // an exponent may be negated without parentheses, as in s^-1
+            let neg = just(Token::Sub)
+                .map_with_span(|_, span: SimpleSpan| span)
+                .or_not()
+                .boxed();
+
+            // the span of a group takes in its parentheses
+            let paren = expr
+                .clone()
+                .delimited_by(just(Token::LParen), just(Token::RParen))
+                .map_with_span(|n: Node<'a>, span: SimpleSpan| Node {
+                    span: span.into_range(),
+                    ..n
+                })
+                .boxed();
+
+            // a bar opens where an operand is expected and closes the innermost
+            // open one otherwise, so |a| + |b| and ||a| - b| parse as written
+            let abs = just(Token::Bar)
+                .map_with_span(|_, span: SimpleSpan| span)
+                .then(expr.clone())
+                .then(just(Token::Bar).or_not())
+                .validate(|((open, val), close), span: SimpleSpan, emit| {
+                    if close.is_none() {
+                        emit.emit(MorphError::custom(
+                            open,
+                            "unclosed '|'",
+                            ErrorType::UndefinedSyntax,
+                        ));
+                    }
+                    Node::new(NodeType::Abs(Box::new(val)), span)
+                })
+                .boxed();
+
+            // a number directly followed by a unit, call or group is multiplied
+            // with it, the exponent binds to that factor only: 5 m^2 is 5 * (m^2)
+            // and 5 m^(1/2) is 5 * (m^(1/2))
+            let num_unit = parse!(num)
+                .then(
+                    choice((paren.clone(), fn_call.clone(), parse!(unit))).then(
+                        just(Token::Pow)
+                            .ignore_then(choice((
+                                paren.clone(),
+                                neg.clone()
+                                    .then(parse!(num))
+                                    .map(|(neg, exp)| negate(neg, exp)),
+                            )))
+                            .or_not(),
+                    ),
+                )
+                .map(|(num, (unit, exp))| match exp {
+                    Some(exp) => num * Node::pow(unit, exp),
+                    None => num * unit,
+                })
+                .boxed();
+
             let atom = choice((
-                expr.clone()
-                    .delimited_by(just(Token::LParen), just(Token::RParen)),
+                paren,
                 scope_parser.delimited_by(just(Token::LCurly), just(Token::RCurly)),
-                parse!(num * unit),
+                abs,
+                list,
+                fn_call,
+                num_unit,
                 parse!(unit),
                 parse!(num),
+                def_fn,
                 def,
             ))
-            .map_err(|err: MorphError| merge_expected!(err::<I>, [Token::NUM, Token::UNIT]));
+            .map_err(|err: MorphError| merge_expected!(err::<I>, [Token::NUM, Token::UNIT]))
+            .boxed();
 
-            let pow = atom
+            let percent = atom
                 .clone()
-                .then_ignore(just(Token::Pow))
-                .then(atom.clone())
-                .map(|(n1, n2)| Node::pow(n1, n2));
+                .foldl(
+                    just(Token::Percent)
+                        .map_with_span(|_, span: SimpleSpan| span)
+                        .repeated(),
+                    |val, span| {
+                        let span = merge_span(&val.span, &span.into_range());
+                        Node::new(NodeType::Percent(val.into()), span)
+                    },
+                )
+                .boxed();
 
-            let pow = choice((pow, atom.clone()));
+            // levels that bind tighter than the prefix operators take a negated
+            // right operand as is, as in s^-1
+            let (tight, loose): (Vec<_>, Vec<_>) = levels()
+                .into_iter()
+                .partition(|(level, _)| *level > PREFIX_PRECEDENCE);
+
+            let tight = tight.into_iter().fold(percent, |operand, (level, assoc)| {
+                let neg = neg.clone();
+                binop_level(level, assoc, operand, move |rhs| {
+                    neg.clone()
+                        .then(rhs)
+                        .map(|(neg, rhs)| negate(neg, rhs))
+                        .boxed()
+                })
+            });
 
+            // a unary '+' is a no-op that only widens the span
             let unary = choice((
-                just(Token::Sub).to(NodeType::UnrySub as fn(Box<Node<'a>>) -> NodeType<'a>),
-                just(Token::Not).to(NodeType::UnryNot as fn(Box<Node<'a>>) -> NodeType<'a>),
+                just(Token::Sub).to(Some(NodeType::UnrySub as fn(Box<Node<'a>>) -> NodeType<'a>)),
+                just(Token::Not).to(Some(NodeType::UnryNot as fn(Box<Node<'a>>) -> NodeType<'a>)),
+                just(Token::Add).to(None),
             ))
-            .map_with_span(|op, span| (op, span))
+            .map_with_span(|op, span: SimpleSpan| (op, span))
             .repeated()
-            .foldr(pow.clone(), |(op, span), val| {
-                    let typ = op(val.into());
-                    Node::new(typ, span)
-                })
-            ;
+            .foldr(tight, |(op, span), val| {
+                let span = merge_span(&span.into_range(), &val.span);
+                match op {
+                    Some(op) => Node::new(op(val.into()), span),
+                    None => Node::new(val.typ, span),
+                }
+            })
+            .boxed();
 
-            let product = unary.clone().foldl(
-                choice((
-                    just(Token::Mul).to(Node::mul as fn(_, _) -> _),
-                    just(Token::Div).to(Node::div as fn(_, _) -> _),
-                ))
-                .then(unary)
-                .repeated(),
-                |lhs, (op, rhs)| op(lhs, rhs),
-            );
+            let logic = loose.into_iter().fold(unary, |operand, (level, assoc)| {
+                binop_level(level, assoc, operand, |rhs| rhs)
+            });
 
-            let sum = product.clone().foldl(
-                choice((
-                    just(Token::Add).to(Node::add as fn(_, _) -> _),
-                    just(Token::Sub).to(Node::sub as fn(_, _) -> _),
-                ))
-                .then(product)
-                .repeated(),
-                |lhs, (op, rhs)| op(lhs, rhs),
-            );
+            // the branches stop short of conversions so the ':' is kept for the
+            // ternary, `c ? a : b ? d : e` nests to the right
+            let ternary = recursive(|ternary| {
+                logic
+                    .clone()
+                    .then(
+                        just(Token::Question)
+                            .ignore_then(ternary.clone())
+                            .then_ignore(just(Token::Colon))
+                            .then(ternary)
+                            .or_not(),
+                    )
+                    .map_with_span(|(cond, branches), span: SimpleSpan| match branches {
+                        Some((if_body, else_body)) => {
+                            let typ = NodeType::if_else(
+                                Box::new(cond),
+                                Box::new(if_body),
+                                Some(Box::new(else_body)),
+                            );
+                            Node::new(typ, span.into_range())
+                        }
+                        None => cond,
+                    })
+            })
+            .boxed();
 
-            let assign = parse!(unit).foldl(
-                choice((
-                    just(Token::Assign).to(Node::assign as fn(&mut Node<'a>, Node<'a>)),
-                    just(Token::AddAssign)
-                        .to(ops::AddAssign::add_assign as fn(&mut Node<'a>, Node<'a>)),
-                    just(Token::SubAssign)
-                        .to(ops::SubAssign::sub_assign as fn(&mut Node<'a>, Node<'a>)),
-                    just(Token::MulAssign)
-                        .to(ops::MulAssign::mul_assign as fn(&mut Node<'a>, Node<'a>)),
-                    just(Token::DivAssign)
-                        .to(ops::DivAssign::div_assign as fn(&mut Node<'a>, Node<'a>)),
-                ))
-                .then(sum.clone())
-                .repeated()
-                .at_least(1),
-                |mut lhs: Node, (op, rhs): (_, Node)| {
-                    op(&mut lhs, rhs);
-                    lhs
-                },
-            );
+            let target_atom = select!(Token::Unit(x) => x.name)
+                .map_with_span(|name, span: SimpleSpan| (name, span))
+                .then(
+                    just(Token::Pow)
+                        .ignore_then(neg.clone())
+                        .then(select!(Token::Num(x) => x))
+                        .or_not(),
+                )
+                .map(|((name, span), exp)| {
+                    let unit = Unit::from(UnitAtom::base(name).with_span(span.into_range()));
+                    match exp {
+                        Some((Some(_), exp)) => unit.pow(-exp),
+                        Some((None, exp)) => unit.pow(exp),
+                        None => unit,
+                    }
+                })
+                .boxed();
 
-            let assign = choice((assign, sum));
-
-            let logic = assign.clone().foldl(
-                choice((
-                    just(Token::Equal)
-                        .to(NodeType::Equal as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                    just(Token::NeEqual)
-                        .to(NodeType::NeEqual as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                    just(Token::GreaterEqual)
-                        .to(NodeType::GreaterEqual
-                            as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                    just(Token::LesserEqual)
-                        .to(NodeType::LesserEqual
-                            as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                    just(Token::Greater)
-                        .to(NodeType::Greater as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                    just(Token::Lesser)
-                        .to(NodeType::Lesser as fn(Box<Node<'a>>, Box<Node<'a>>) -> NodeType<'a>),
-                ))
-                .then(assign.clone())
-                .repeated(),
-                |lhs: Node, (op, rhs): (_, Node)| {
-                    let span = merge_span(&lhs.span, &rhs.span);
-                    let typ = op(lhs.into(), rhs.into());
-                    Node::new(typ, span)
-                },
-            );
+            let target = target_atom
+                .clone()
+                .foldl(
+                    choice((
+                        just(Token::Mul).to(ops::Mul::mul as fn(Unit<'a>, Unit<'a>) -> Unit<'a>),
+                        just(Token::Div).to(ops::Div::div as fn(Unit<'a>, Unit<'a>) -> Unit<'a>),
+                    ))
+                    .then(target_atom)
+                    .repeated(),
+                    |lhs, (op, rhs)| op(lhs, rhs),
+                )
+                .map_with_span(|unit, span: SimpleSpan| (unit, span))
+                .boxed();
+
+            // converting binds loosest, `a + b : km` converts the sum
+            let convert = ternary
+                .foldl(
+                    just(Token::Colon).ignore_then(target).repeated(),
+                    |expr: Node, (target, span): (Unit, SimpleSpan)| {
+                        let span = merge_span(&expr.span, &span.into_range());
+                        let typ = NodeType::Convert {
+                            expr: expr.into(),
+                            target,
+                        };
+                        Node::new(typ, span)
+                    },
+                )
+                .boxed();
+
+            // atom parses a Scope here, as the '{' was peeked
+            let body = just(Token::LCurly).rewind().ignore_then(atom).boxed();
 
             let r#if = just(Token::If)
                 .ignore_then(expr)
-                .then_ignore(just(Token::LCurly).rewind())
-                .then(atom)
-                .map_with_span(|(cond, scope): (Node, Node), span: SimpleSpan| {
-                    if let NodeType::Scope(_) = scope.typ {
-                        Node::new(
-                            NodeType::if_else(cond.into(), scope.into(), None),
-                            span.into_range(),
-                        )
-                    } else {
-                        unreachable!();
-                    }
-                });
+                .then(body.clone())
+                .then(just(Token::Else).ignore_then(body).or_not())
+                .map_with_span(
+                    |((cond, if_body), else_body): ((Node, Node), Option<Node>),
+                     span: SimpleSpan| {
+                        let typ =
+                            NodeType::if_else(cond.into(), if_body.into(), else_body.map(Box::new));
+                        Node::new(typ, span.into_range())
+                    },
+                );
 
-            choice((logic, r#if))
+            choice((convert, r#if))
         });
 
         choice((expr, Self::syntax_err())).boxed()
@@ -205,11 +586,25 @@ impl<'a> Node<'a> {
     where
         I: ValueInput<'a, Token = Token<'a>, Span = SimpleSpan>,
     {
+        // a '|' that neither continues an or nor closes an abs, the rest of the
+        // statement is skipped
+        let stray_bar = just(Token::Bar)
+            .validate(|_, span: SimpleSpan, emit| {
+                emit.emit(MorphError::custom(
+                    span,
+                    "unexpected '|', an operand of '|' that starts with a sign or bar needs parentheses",
+                    ErrorType::UndefinedSyntax,
+                ))
+            })
+            .then(none_of([Token::NL, Token::RCurly]).repeated())
+            .boxed();
+
         let scope = recursive(|scope| {
             just(Token::NL)
                 .repeated()
                 .ignore_then(
                     Self::expression(scope)
+                        .then_ignore(stray_bar.or_not())
                         .then_ignore(choice((
                             just(Token::NL).repeated().at_least(1),
                             just(Token::RCurly).to(()).rewind(),
@@ -241,8 +636,11 @@ impl<'a> Node<'a> {
 
 #[cfg(test)]
 mod test {
+    use super::{precedence, Assoc, BINARY_OPS};
     use crate::{error::*, morph::test_utils::*, types::*};
 
+    use rust_decimal_macros::dec;
+
     #[test]
     fn basic_expr() {
         eq!(nodes(""), bod!());
@@ -277,10 +675,153 @@ mod test {
         eq!(nodes("m + s"), bod!(u("m") + u("s")));
     }
 
+    #[test]
+    fn pow() {
+        eq!(nodes("m ^ 2"), bod!(u("m") ^ n(2)));
+        eq!(nodes("2 ^ 3 ^ 2"), bod!(n(2) ^ (n(3) ^ n(2))));
+        eq!(nodes("2 * 3 ^ 2"), bod!(n(2) * (n(3) ^ n(2))));
+        eq!(nodes("(2 ^ 3) ^ 2"), bod!((n(2) ^ n(3)) ^ n(2)));
+    }
+
+    #[test]
+    fn precedence_table() {
+        let prec = |tok| precedence(&tok).unwrap();
+
+        assert!(prec(Token::Pow).0 > prec(Token::Mul).0);
+        eq!(prec(Token::Mul), prec(Token::Div));
+        eq!(prec(Token::Mul), prec(Token::Mod));
+        assert!(prec(Token::Mul).0 > prec(Token::Add).0);
+        assert!(prec(Token::Add).0 > prec(Token::Shl).0);
+        assert!(prec(Token::Shl).0 > prec(Token::Amp).0);
+        assert!(prec(Token::Amp).0 > prec(Token::Bar).0);
+        assert!(prec(Token::Bar).0 > prec(Token::Assign).0);
+        assert!(prec(Token::Assign).0 > prec(Token::Equal).0);
+        eq!(prec(Token::Pow).1, Assoc::Right);
+        eq!(prec(Token::Sub).1, Assoc::Left);
+        eq!(precedence(&Token::Comma), None);
+        eq!(precedence(&Token::NUM), None);
+
+        // the parser groups every operator the way the table says
+        let stmt = |src: &str| match parse(src).0.unwrap().typ {
+            NodeType::Scope(stmts) => stmts[0].to_string(),
+            _ => unreachable!(),
+        };
+        for op in BINARY_OPS {
+            let grouped = match prec(op.clone()).1 {
+                Assoc::Left => format!("((a {op} b) {op} c)"),
+                Assoc::Right => format!("(a {op} (b {op} c))"),
+            };
+            eq!(stmt(&format!("a {op} b {op} c")), grouped);
+        }
+    }
+
+    #[test]
+    fn bitwise() {
+        let bin =
+            |typ: fn(Box<Node<'static>>, Box<Node<'static>>) -> NodeType<'static>,
+             lhs: Node<'static>,
+             rhs: Node<'static>| Node::new(typ(lhs.into(), rhs.into()), 0..0);
+        let abs = |val: Node<'static>| Node::new(NodeType::Abs(val.into()), 0..0);
+
+        eq!(nodes("5 & 3"), bod!(bin(NodeType::BitAnd, n(5), n(3))));
+        eq!(nodes("1 << 4"), bod!(bin(NodeType::Shl, n(1), n(4))));
+        eq!(
+            nodes("1 | 2 & 3 >> 1 + 1"),
+            bod!(bin(
+                NodeType::BitOr,
+                n(1),
+                bin(
+                    NodeType::BitAnd,
+                    n(2),
+                    bin(NodeType::Shr, n(3), n(1) + n(1))
+                )
+            ))
+        );
+        eq!(
+            nodes("x = 5 & 3 == 1"),
+            bod!(bin(
+                NodeType::Equal,
+                Node::new(
+                    NodeType::Assign("x", bin(NodeType::BitAnd, n(5), n(3)).into()),
+                    0..0
+                ),
+                n(1)
+            ))
+        );
+
+        // bars still close an abs when a sign, another bar or nothing follows
+        eq!(nodes("|a| - |b|"), bod!(abs(u("a")) - abs(u("b"))));
+        eq!(nodes("|2| - |3|"), bod!(abs(n(2)) - abs(n(3))));
+        eq!(
+            nodes("|a | b|"),
+            bod!(abs(bin(NodeType::BitOr, u("a"), u("b"))))
+        );
+        eq!(
+            nodes("|-5| | 2"),
+            bod!(bin(NodeType::BitOr, abs(-n(5)), n(2)))
+        );
+        eq!(
+            nodes("1 | (|x|)"),
+            bod!(bin(NodeType::BitOr, n(1), abs(u("x"))))
+        );
+        eq!(nodes("5 | (-3)"), bod!(bin(NodeType::BitOr, n(5), -n(3))));
+
+        let err = |src| match parse(src).1.as_slice() {
+            [err] => (err.to_string(), err.span.into_range()),
+            errs => panic!("expected a single error, found: {:?}", errs),
+        };
+        let msg =
+            "unexpected '|', an operand of '|' that starts with a sign or bar needs parentheses";
+        eq!(err("5 | -3"), (msg.to_owned(), 2..3));
+        eq!(err("|x| | |x|"), (msg.to_owned(), 4..5));
+
+        assert!(!parse("1 <<").1.is_empty());
+    }
+
+    #[test]
+    fn implicit_mul() {
+        eq!(nodes("2m"), bod!(n(2) * u("m")));
+        eq!(nodes("2 pi"), bod!(n(2) * u("pi")));
+        eq!(nodes("3(1+1)"), bod!(n(3) * (n(1) + n(1))));
+        eq!(nodes("3 (x) ^ 2 + 1"), bod!(n(3) * (u("x") ^ n(2)) + n(1)));
+        eq!(nodes("2 sqrt(x)"), bod!(n(2) * call("sqrt", vec![u("x")])));
+        eq!(nodes("2e3"), bod!(n(2000)));
+        eq!(nodes("2e3 m"), bod!(n(2000) * u("m")));
+    }
+
+    #[test]
+    fn unit_exponent() {
+        eq!(nodes("5 m^2"), bod!(n(5) * (u("m") ^ n(2))));
+        eq!(nodes("5 m^-2"), bod!(n(5) * (u("m") ^ -n(2))));
+        eq!(nodes("s^-1"), bod!(u("s") ^ -n(1)));
+        eq!(nodes("m^2 / m"), bod!((u("m") ^ n(2)) / u("m")));
+        eq!(nodes("2^-x^2"), bod!(n(2) ^ -(u("x") ^ n(2))));
+        eq!(nodes("(5 m)^2"), bod!((n(5) * u("m")) ^ n(2)));
+        eq!(nodes("5 m^(1/2)"), bod!(n(5) * (u("m") ^ (n(1) / n(2)))));
+        eq!(nodes("3 s^(-2)"), bod!(n(3) * (u("s") ^ -n(2))));
+    }
+
+    #[test]
+    fn compare() {
+        let cmp = |typ: fn(_, _) -> _, lhs: Node<'static>, rhs: Node<'static>| {
+            Node::new(typ(Box::new(lhs), Box::new(rhs)), 0..0)
+        };
+
+        eq!(nodes("1 < 2"), bod!(cmp(NodeType::Lesser, n(1), n(2))));
+        eq!(
+            nodes("3 m == 3 m"),
+            bod!(cmp(NodeType::Equal, n(3) * u("m"), n(3) * u("m")))
+        );
+        eq!(
+            nodes("a + 1 >= b * 2"),
+            bod!(cmp(NodeType::GreaterEqual, u("a") + n(1), u("b") * n(2)))
+        );
+    }
+
     #[test]
     fn assign() {
         let mut a = u("a");
-        a.assign(n(2) / n(3) + n(2));
+        a.assign(n(2) / n(3) + n(2)).unwrap();
         eq!(nodes("a = 2 / 3 + 2"), bod!(a));
 
         let mut b = u("b");
@@ -294,18 +835,77 @@ mod test {
         let mut d = u("d");
         d /= n(0);
         eq!(nodes("d /= 0"), bod!(d));
+
+        let mut e = u("e");
+        e ^= n(2) ^ n(3);
+        eq!(nodes("e ^= 2 ^ 3"), bod!(e));
+    }
+
+    #[test]
+    fn assign_target() {
+        let assign = |name, val: Node<'static>| Node::new(NodeType::Assign(name, val.into()), 0..0);
+
+        eq!(nodes("x = y = 2"), bod!(assign("x", assign("y", n(2)))));
+        eq!(
+            nodes("x = y += 2 m"),
+            bod!(assign(
+                "x",
+                Node::new(NodeType::AddAssign("y", (n(2) * u("m")).into()), 0..0)
+            ))
+        );
+
+        let (root, errors) = parse("(a + b) = 3\nx = 1 = 2\n2 m += 1");
+        let kinds: Vec<_> = errors
+            .iter()
+            .map(|e| (e.typ, e.span.into_range()))
+            .collect();
+        eq!(
+            kinds,
+            vec![
+                (ErrorType::InvalidAssignTarget, 0..7),
+                (ErrorType::InvalidAssignTarget, 16..17),
+                (ErrorType::InvalidAssignTarget, 22..25),
+            ]
+        );
+        eq!(errors[0].to_string(), "can't assign to 'a + b'");
+
+        let NodeType::Scope(stmts) = root.unwrap().typ else {
+            unreachable!()
+        };
+        eq!(stmts.len(), 3);
+        eq!(stmts[0].typ, NodeType::ParseError);
+        eq!(stmts[0].span, 0..11);
+
+        let mut target = n(1) + n(2);
+        assert!(target.assign(n(3)).is_err());
+        eq!(target, n(1) + n(2));
+        target += n(3);
+        assert!(target.is_err());
     }
 
     #[test]
     fn unary_op() {
         eq!(nodes("2 meter"), bod!(n(2) * u("meter")));
         eq!(nodes("345meter"), bod!(n(345) * u("meter")));
-        eq!(nodes("- meter"), bod!(n(-1) * u("meter")));
-        eq!(
-            nodes("- meter * -s"),
-            bod!((n(-1) * u("meter")) * (n(-1) * u("s")))
-        );
-        assert!(!parse("+ meter").1.is_empty());
+        eq!(nodes("- meter"), bod!(-u("meter")));
+        eq!(nodes("- meter * -s"), bod!(-u("meter") * -u("s")));
+        eq!(nodes("2 - -3"), bod!(n(2) - -n(3)));
+        eq!(nodes("--4"), bod!(-(-n(4))));
+        eq!(nodes("!-m"), bod!(!(-u("m"))));
+        eq!(nodes("+ meter"), bod!(u("meter")));
+        eq!(nodes("+5 - +2"), bod!(n(5) - n(2)));
+        eq!(nodes("-+m"), bod!(-u("m")));
+        eq!(parse("+ meter").0.unwrap().span, 0..7);
+
+        let root = parse("2 - -3").0.unwrap();
+        let NodeType::Scope(nodes) = root.typ else {
+            panic!("expected a scope, found: {:?}", root.typ)
+        };
+        let NodeType::Sub(_, rhs) = &nodes[0].typ else {
+            panic!("expected a subtraction, found: {:?}", nodes[0].typ)
+        };
+        eq!(rhs.span, 4..6);
+        eq!(parse("- meter").0.unwrap().span, 0..7);
     }
 
     #[test]
@@ -315,12 +915,298 @@ mod test {
 
     #[test]
     fn error_recover() {
-        eq!(parse("def ; meter; +second; dir; --m").1.len(), 2)
+        eq!(parse("def ; meter; *second; dir; --m").1.len(), 2)
     }
 
     #[test]
     fn error_type() {
         eq!(parse("$").1[0].typ, ErrorType::CouldNotLex);
-        eq!(parse("+ meter").1[0].typ, ErrorType::UndefinedSyntax);
+        eq!(parse("* meter").1[0].typ, ErrorType::UndefinedSyntax);
+
+        let errs = parse("1e99").1;
+        eq!(errs[0].typ, ErrorType::CouldNotLex);
+        eq!(errs[0].to_string(), "number literal out of range: 1e99");
+    }
+
+    #[test]
+    fn dangling_operator() {
+        let errs = parse("5 +").1;
+        eq!(errs.len(), 1);
+        eq!(errs[0].typ, ErrorType::ExpectedOperand);
+        eq!(errs[0].span.into_range(), 2..3);
+        eq!(errs[0].to_string(), "expected an operand after '+'");
+
+        eq!(parse("x = 2 *\ny").1[0].span.into_range(), 6..7);
+        eq!(parse("{ 1 == }").1[0].span.into_range(), 4..6);
+        eq!(parse("f(1 -, 2)").1[0].span.into_range(), 4..5);
+        eq!(parse("x =").1[0].typ, ErrorType::ExpectedOperand);
+
+        // the rest of the statement still parses
+        eq!(
+            nodes("3 -"),
+            bod!(Node::new(
+                NodeType::Sub(n(3).into(), Node::err(0..0).into()),
+                0..0
+            ))
+        );
+    }
+
+    #[test]
+    fn fn_call() {
+        eq!(nodes("sqrt(9)"), bod!(call("sqrt", vec![n(9)])));
+        eq!(
+            nodes("max(1, 2, 3)"),
+            bod!(call("max", vec![n(1), n(2), n(3)]))
+        );
+        eq!(nodes("f()"), bod!(call("f", vec![])));
+        eq!(
+            nodes("f(a + 1) * 2"),
+            bod!(call("f", vec![u("a") + n(1)]) * n(2))
+        );
+        eq!(
+            nodes("f(g(x))"),
+            bod!(call("f", vec![call("g", vec![u("x")])]))
+        );
+        eq!(call("max", vec![n(1), u("x")]).to_string(), "max(1, x)");
+
+        assert!(!parse("max(1, 2,)").1.is_empty());
+        assert!(!parse("max(, 1)").1.is_empty());
+    }
+
+    #[test]
+    fn def_fn() {
+        let def_fn = |name, params, body| {
+            let typ = NodeType::DefFn {
+                name,
+                params,
+                body: Node::new(body, 0..0).into(),
+            };
+            Node::new(typ, 0..0)
+        };
+
+        eq!(
+            nodes("def f(x, y) { x + y }"),
+            bod!(def_fn("f", vec!["x", "y"], bod!(u("x") + u("y"))))
+        );
+        eq!(
+            nodes("def g() { 1 }"),
+            bod!(def_fn("g", vec![], bod!(n(1))))
+        );
+        eq!(nodes("def m"), bod!(d("m")));
+
+        assert!(!parse("def f(x + 1) { x }").1.is_empty());
+    }
+
+    #[test]
+    fn abs_bars() {
+        let abs = |val: Node<'static>| Node::new(NodeType::Abs(val.into()), 0..0);
+
+        eq!(nodes("|x|"), bod!(abs(u("x"))));
+        eq!(nodes("|(-3) * m|"), bod!(abs(-n(3) * u("m"))));
+        eq!(nodes("|-3 m|"), bod!(abs(-(n(3) * u("m")))));
+        eq!(nodes("|a| + |b|"), bod!(abs(u("a")) + abs(u("b"))));
+        eq!(nodes("2 * |x - 1|"), bod!(n(2) * abs(u("x") - n(1))));
+        eq!(nodes("||a| - b|"), bod!(abs(abs(u("a")) - u("b"))));
+        eq!(nodes("||a||"), bod!(abs(abs(u("a")))));
+
+        let errs = parse("1 + |x").1;
+        eq!(errs.len(), 1);
+        eq!(errs[0].span.into_range(), 4..5);
+    }
+
+    #[test]
+    fn convert() {
+        let convert = |expr: Node<'static>, target| {
+            let typ = NodeType::Convert {
+                expr: expr.into(),
+                target,
+            };
+            Node::new(typ, 0..0)
+        };
+        let unit = |name| Unit::from(UnitAtom::base(name));
+
+        eq!(
+            nodes("1000 m : km"),
+            bod!(convert(n(1000) * u("m"), unit("km")))
+        );
+        eq!(
+            nodes("a + b : km / h"),
+            bod!(convert(u("a") + u("b"), unit("km") / unit("h")))
+        );
+        eq!(
+            nodes("x : m^2 * s^-1"),
+            bod!(convert(
+                u("x"),
+                unit("m").pow(dec!(2)) * unit("s").pow(dec!(-1))
+            ))
+        );
+        eq!(
+            parse("1000 m : km / h")
+                .0
+                .unwrap()
+                .display_minimal()
+                .to_string(),
+            "{\n1000 * m : km * h^-1\n}"
+        );
+
+        assert!(!parse("1 m : 2").1.is_empty());
+    }
+
+    #[test]
+    fn percent() {
+        let pct = |val: Node<'static>| Node::new(NodeType::Percent(val.into()), 0..0);
+
+        eq!(nodes("50%"), bod!(pct(n(50))));
+        eq!(nodes("(20)%"), bod!(pct(n(20))));
+        eq!(nodes("2 * 50%"), bod!(n(2) * pct(n(50))));
+        eq!(nodes("50% * 2"), bod!(pct(n(50)) * n(2)));
+        eq!(nodes("5 m%"), bod!(pct(n(5) * u("m"))));
+        eq!(nodes("-10%"), bod!(-pct(n(10))));
+
+        let (root, _) = parse("1 + 20%");
+        let NodeType::Scope(stmts) = root.unwrap().typ else {
+            unreachable!()
+        };
+        eq!(stmts[0].span, 0..7);
+    }
+
+    #[test]
+    fn if_else() {
+        let if_else = |cond: Node<'static>, if_body, else_body: Option<NodeType<'static>>| {
+            let typ = NodeType::if_else(
+                cond.into(),
+                Node::new(if_body, 0..0).into(),
+                else_body.map(|b| Node::new(b, 0..0).into()),
+            );
+            Node::new(typ, 0..0)
+        };
+
+        eq!(nodes("if x { 1 }"), bod!(if_else(u("x"), bod!(n(1)), None)));
+        eq!(
+            nodes("if x > 2 { 1 } else { y = 2; y }"),
+            bod!(if_else(
+                Node::new(NodeType::Greater(u("x").into(), n(2).into()), 0..0),
+                bod!(n(1)),
+                Some(bod!(
+                    Node::new(NodeType::Assign("y", n(2).into()), 0..0),
+                    u("y")
+                ))
+            ))
+        );
+
+        assert!(!parse("if x { 1 } else 2").1.is_empty());
+        assert!(!parse("if x 1").1.is_empty());
+    }
+
+    #[test]
+    fn ternary() {
+        let tern = |cond: Node<'static>, if_body: Node<'static>, else_body: Node<'static>| {
+            let typ = NodeType::if_else(cond.into(), if_body.into(), Some(else_body.into()));
+            Node::new(typ, 0..0)
+        };
+
+        eq!(nodes("c ? a : b"), bod!(tern(u("c"), u("a"), u("b"))));
+        eq!(
+            nodes("a ? b : c ? d : e"),
+            bod!(tern(u("a"), u("b"), tern(u("c"), u("d"), u("e"))))
+        );
+        eq!(
+            nodes("a ? b ? c : d : e"),
+            bod!(tern(u("a"), tern(u("b"), u("c"), u("d")), u("e")))
+        );
+        eq!(
+            nodes("x > 1 ? x + 1 : 0"),
+            bod!(tern(
+                Node::new(NodeType::Greater(u("x").into(), n(1).into()), 0..0),
+                u("x") + n(1),
+                n(0)
+            ))
+        );
+
+        let root = parse("c ? a : b : km").0.unwrap();
+        let NodeType::Scope(stmts) = root.typ else {
+            unreachable!()
+        };
+        assert!(matches!(stmts[0].typ, NodeType::Convert { .. }));
+        eq!(stmts[0].span, 0..14);
+
+        assert!(!parse("c ? a").1.is_empty());
+    }
+
+    #[test]
+    fn list() {
+        let list = |items| Node::new(NodeType::List(items), 0..0);
+
+        eq!(nodes("[1, 2, 3]"), bod!(list(vec![n(1), n(2), n(3)])));
+        eq!(nodes("[]"), bod!(list(vec![])));
+        eq!(
+            nodes("[x + 1, y,]"),
+            bod!(list(vec![u("x") + n(1), u("y")]))
+        );
+        eq!(nodes("[1, 2] * 2"), bod!(list(vec![n(1), n(2)]) * n(2)));
+        eq!(parse("[1, 2]").0.unwrap().to_string(), "{\n[1, 2]\n}");
+
+        assert!(!parse("[1, 2").1.is_empty());
+    }
+
+    #[test]
+    fn modulo() {
+        eq!(nodes("7 mod 3"), bod!(n(7) % n(3)));
+        eq!(nodes("a * b mod c"), bod!(u("a") * u("b") % u("c")));
+        eq!(nodes("a + b mod c"), bod!(u("a") + u("b") % u("c")));
+        eq!(nodes("module"), bod!(u("module")));
+    }
+
+    #[test]
+    fn display_minimal() {
+        let minimal = |code| match parse(code).0.unwrap().typ {
+            NodeType::Scope(stmts) => stmts[0].display_minimal().to_string(),
+            _ => unreachable!(),
+        };
+        let full = |code| match parse(code).0.unwrap().typ {
+            NodeType::Scope(stmts) => stmts[0].to_string(),
+            _ => unreachable!(),
+        };
+
+        eq!(full("1 + 2 + 3"), "((1 + 2) + 3)");
+        eq!(minimal("1 + 2 + 3"), "1 + 2 + 3");
+        eq!(minimal("1 + (2 + 3)"), "1 + (2 + 3)");
+        eq!(minimal("1 - (2 - 3)"), "1 - (2 - 3)");
+        eq!(minimal("2 * (3 + 4)"), "2 * (3 + 4)");
+        eq!(minimal("2 * 3 + 4"), "2 * 3 + 4");
+        eq!(minimal("a / (b * c)"), "a / (b * c)");
+        eq!(minimal("2 ^ 3 ^ 2"), "2 ^ 3 ^ 2");
+        eq!(minimal("2 ^ (3 ^ 2)"), "2 ^ 3 ^ 2");
+        eq!(full("(2 ^ 3) ^ 2"), "((2 ^ 3) ^ 2)");
+        eq!(minimal("(2 ^ 3) ^ 2"), "(2 ^ 3) ^ 2");
+        eq!(minimal("-x ^ 2"), "-x ^ 2");
+        eq!(minimal("(-x) ^ 2"), "(-x) ^ 2");
+        eq!(minimal("-(a + b) * c"), "-(a + b) * c");
+        eq!(minimal("(1 + 2)%"), "(1 + 2)%");
+        eq!(
+            minimal("x = 1 + 2 * f(3, 4 - 1)"),
+            "x = 1 + 2 * f(3, 4 - 1)"
+        );
+        eq!(minimal("a + 1 == b * 2"), "a + 1 == b * 2");
+        eq!(minimal("7 mod (2 mod 3)"), "7 mod (2 mod 3)");
+        eq!(minimal("1 | (-2)"), "1 | (-2)");
+        eq!(minimal("(1 | 2) | 3"), "1 | 2 | 3");
+
+        for code in [
+            "1 - (2 - 3) * 4",
+            "2 ^ -(1 + x)",
+            "-(-4)",
+            "a / b / (c / d)",
+            "1 | (-2) & 3",
+            "(1 | 2) & 3 << 1",
+            "a | (!b)",
+            "a | (|b|)",
+            "a | (b | (-c))",
+            "x = 1 | 2",
+            "|a | b| | 1",
+        ] {
+            let reparsed = minimal(code);
+            eq!(nodes(code), nodes(&reparsed));
+        }
     }
 }
@@ -1,4 +1,13 @@
-use std::{cmp, fmt, ops, ops::Range, str::FromStr, stringify};
+use std::{
+    cmp,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    ops,
+    ops::Range,
+    str::FromStr,
+    stringify,
+};
 
 use logos::Logos;
 use paste::paste;
@@ -11,14 +20,172 @@ use rust_decimal_macros::dec;
 pub type NumType = Decimal;
 
 use crate::error::*;
+use crate::parser::{precedence, Assoc, POSTFIX_PRECEDENCE, PREFIX_PRECEDENCE};
 
-fn decimal<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> Option<Decimal> {
-    Decimal::from_str(lex.slice()).ok()
+// a ',' can't be both a decimal separator and a delimiter, with decimal_comma
+// set every ',' outside of a number is a lexer error
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LexOptions {
+    pub decimal_comma: bool,
+}
+
+fn decimal<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> Result<Decimal, LexError> {
+    let valid_sep =
+        |digits: &str| !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__");
+
+    // `0x` on its own is a literal without digits, but `0bar` is 0 bar
+    if lex.slice() == "0" {
+        let mut rest = lex.remainder().chars();
+        let ident = |c: char| c.is_alphanumeric() || c == '_';
+        if matches!(rest.next(), Some('x' | 'b')) && !rest.next().is_some_and(ident) {
+            lex.bump(1);
+            return Err(LexError::MissingDigits);
+        }
+    }
+
+    if lex.extras.decimal_comma {
+        if lex.slice().contains('.') {
+            return Err(LexError::InvalidSeparator);
+        }
+
+        let rest = lex.remainder();
+        if let Some(frac) = rest.strip_prefix(',') {
+            let len = frac.find(|c: char| !c.is_ascii_digit() && c != '_');
+            let len = len.unwrap_or(frac.len());
+            if len > 0 {
+                lex.bump(len + 1);
+            }
+        }
+    }
+
+    let num = lex.slice().replace(',', ".");
+
+    if !num.split('.').all(valid_sep) {
+        return Err(LexError::InvalidSeparator);
+    }
+
+    Decimal::from_str(&num.replace('_', "")).map_err(|_| LexError::NumOutOfRange)
+}
+
+fn comma<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> Result<(), LexError> {
+    match lex.extras.decimal_comma {
+        true => Err(LexError::CommaDelimiter),
+        false => Ok(()),
+    }
+}
+
+fn decimal_sci<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> Result<Decimal, LexError> {
+    Decimal::from_scientific(lex.slice()).map_err(|_| LexError::NumOutOfRange)
+}
+
+// the digits of 0x.. and 0b.. literals, parsed as integers
+fn radix<'a>(lex: &mut logos::Lexer<'a, Token<'a>>, radix: u32) -> Result<Decimal, LexError> {
+    let digits = &lex.slice()[2..];
+
+    i128::from_str_radix(digits, radix)
+        .ok()
+        .and_then(Decimal::from_i128)
+        .ok_or(LexError::NumOutOfRange)
+}
+
+fn unit_ident<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> UnitIdent<'a> {
+    UnitIdent::new(lex.slice())
+}
+
+// `US$` names a unit the identifier rules reject, it is never split into a prefix
+fn quoted_unit<'a>(lex: &mut logos::Lexer<'a, Token<'a>>) -> UnitIdent<'a> {
+    let slice = lex.slice();
+    UnitIdent::plain(&slice[1..slice.len() - 1])
+}
+
+const SI_PREFIXES: [(&str, Decimal); 7] = [
+    ("k", dec!(1000)),
+    ("M", dec!(1000000)),
+    ("m", dec!(0.001)),
+    ("u", dec!(0.000001)),
+    ("n", dec!(0.000000001)),
+    ("c", dec!(0.01)),
+    ("d", dec!(0.1)),
+];
+
+const KNOWN_BASE_UNITS: [&str; 17] = [
+    "m", "s", "g", "A", "K", "mol", "cd", "L", "Hz", "N", "Pa", "J", "W", "C", "V", "in", "ft",
+];
+
+// temperature scales with an offset, adding them is ambiguous
+const AFFINE_UNITS: [&str; 2] = ["degC", "degF"];
+
+// names that look prefixed but are units in their own right, e.g. min is not milli-inch
+const PREFIX_EXCLUSIONS: [&str; 1] = ["min"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UnitIdent<'a> {
+    pub name: &'a str,
+    pub base: &'a str,
+    pub prefix: Decimal,
+}
+
+impl<'a> UnitIdent<'a> {
+    pub const fn plain(name: &'a str) -> Self {
+        Self {
+            name,
+            base: name,
+            prefix: dec!(1),
+        }
+    }
+
+    pub fn new(name: &'a str) -> Self {
+        if PREFIX_EXCLUSIONS.contains(&name) || KNOWN_BASE_UNITS.contains(&name) {
+            return Self::plain(name);
+        }
+
+        for (prefix, factor) in SI_PREFIXES {
+            if let Some(base) = name.strip_prefix(prefix) {
+                if KNOWN_BASE_UNITS.contains(&base) {
+                    return Self {
+                        name,
+                        base,
+                        prefix: factor,
+                    };
+                }
+            }
+        }
+
+        Self::plain(name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum LexError {
+    #[default]
+    UnknownChar,
+    NumOutOfRange,
+    InvalidSeparator,
+    MissingDigits,
+    CommaDelimiter,
+    UnterminatedQuote,
+    EmptyQuote,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnknownChar => write!(f, "unsupported character"),
+            LexError::NumOutOfRange => write!(f, "number literal out of range"),
+            LexError::InvalidSeparator => write!(f, "invalid digit separator"),
+            LexError::MissingDigits => write!(f, "missing digits after radix prefix"),
+            LexError::CommaDelimiter => write!(f, "',' only separates decimals in this locale"),
+            LexError::UnterminatedQuote => write!(f, "unterminated '`' in unit name"),
+            LexError::EmptyQuote => write!(f, "missing unit name between '`'"),
+        }
+    }
 }
 
 #[derive(Logos, Debug, PartialEq, Clone)]
-#[logos(skip r"([ \t\f]+|//.*)")]
-#[logos(subpattern unicode_ident = r"\p{XID_Start}\p{XID_Continue}*")]
+#[logos(error = LexError)]
+#[logos(extras = LexOptions)]
+#[logos(skip r"([ \t\f]+|//.*|#[^\n]*)")]
+#[logos(subpattern unicode_ident = r"[\p{XID_Start}_]\p{XID_Continue}*")]
 #[logos(subpattern ascii_ident = r"[_a-zA-Z][_0-9a-zA-Z]*")]
 pub enum Token<'a> {
     #[token("*")]
@@ -31,6 +198,8 @@ pub enum Token<'a> {
     Sub,
     #[token("^")]
     Pow,
+    #[token("%")]
+    Percent,
     #[token("!")]
     Not,
     #[token("=")]
@@ -43,6 +212,8 @@ pub enum Token<'a> {
     MulAssign,
     #[token("/=")]
     DivAssign,
+    #[token("^=")]
+    PowAssign,
 
     #[token("==")]
     Equal,
@@ -56,11 +227,29 @@ pub enum Token<'a> {
     Greater,
     #[token("<")]
     Lesser,
+    #[token("&")]
+    Amp,
+    #[token("<<")]
+    Shl,
+    #[token(">>")]
+    Shr,
 
+    #[token("|")]
+    Bar,
+    #[token(":")]
+    Colon,
+    #[token("?")]
+    Question,
+    #[token(",", comma)]
+    Comma,
     #[token("(")]
     LParen,
     #[token(")")]
     RParen,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
     #[token("{")]
     LCurly,
     #[token("}")]
@@ -72,23 +261,33 @@ pub enum Token<'a> {
     If,
     #[regex("else")]
     Else,
+    #[regex("mod")]
+    Mod,
 
-    #[regex("(?&unicode_ident)", |lex| lex.slice())]
-    Unit(&'a str),
+    #[regex("(?&unicode_ident)", unit_ident)]
+    #[regex(r"`[^`\n]+`", quoted_unit)]
+    #[regex(r"`[^`\n]*", |_| Err(LexError::UnterminatedQuote))]
+    #[regex("``", |_| Err(LexError::EmptyQuote))]
+    Unit(UnitIdent<'a>),
 
-    #[regex(r"(([0-9]+)(\.[0-9]+))", decimal)]
-    #[regex("[0-9]+", decimal)]
+    // a '_' only counts as a separator next to a digit, on its own it is a unit
+    #[regex(r"[0-9_]*[0-9][0-9_]*\.[0-9_]+", decimal, priority = 2)]
+    #[regex("[0-9_]*[0-9][0-9_]*", decimal, priority = 2)]
+    #[regex(r"[0-9]+(\.[0-9]+)?[eE][+-]?[0-9]+", decimal_sci)]
+    #[regex("0x[0-9a-fA-F]+", |lex| radix(lex, 16))]
+    #[regex("0b[01]+", |lex| radix(lex, 2))]
     Num(NumType),
 
-    #[regex(r";|\n")]
+    // \r\n is a single separator, a lone \r ends a line as well
+    #[regex(r";|\r?\n|\r")]
     NL,
 
-    LexErr(&'a str),
+    LexErr((&'a str, LexError)),
 }
 
 impl<'a> Token<'a> {
     pub const NUM: Self = Self::Num(dec!(0));
-    pub const UNIT: Self = Self::Unit("...");
+    pub const UNIT: Self = Self::Unit(UnitIdent::plain("..."));
 }
 
 impl fmt::Display for Token<'_> {
@@ -101,61 +300,90 @@ impl fmt::Display for Token<'_> {
             Add => "+",
             Sub => "-",
             Pow => "^",
+            Percent => "%",
             Not => "!",
             Assign => "=",
             AddAssign => "+=",
             SubAssign => "-=",
             MulAssign => "*=",
             DivAssign => "/=",
+            PowAssign => "^=",
+            Bar => "|",
+            Colon => ":",
+            Question => "?",
+            Comma => ",",
             LParen => "(",
             RParen => ")",
+            LBracket => "[",
+            RBracket => "]",
             LCurly => "{",
             RCurly => "}",
             Def => "def",
             If => "if",
             Else => "else",
+            Mod => "mod",
             Unit(_) => "UNIT",
             Num(_) => "NUM",
             NL => r"(\n or ;)",
-            LexErr(msg) => return write!(f, "Lexer Error: {msg}"),
+            LexErr((slice, err)) => return write!(f, "Lexer Error: {err}: {slice}"),
             Equal => "==",
             NeEqual => "!=",
             GreaterEqual => ">=",
             LesserEqual => "<=",
             Greater => ">",
             Lesser => "<",
+            Amp => "&",
+            Shl => "<<",
+            Shr => ">>",
         };
 
         write!(f, "{}", res)
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum NodeType<'a> {
     Def(&'a str),
+    DefFn {
+        name: &'a str,
+        params: Vec<&'a str>,
+        body: Box<Node<'a>>,
+    },
     IfElse {
         cond: Box<Node<'a>>,
-        ifBody: Box<Node<'a>>,
-        elseBody: Option<Box<Node<'a>>>,
+        if_body: Box<Node<'a>>,
+        else_body: Option<Box<Node<'a>>>,
     },
 
     Add(Box<Node<'a>>, Box<Node<'a>>),
     Sub(Box<Node<'a>>, Box<Node<'a>>),
     Mul(Box<Node<'a>>, Box<Node<'a>>),
     Div(Box<Node<'a>>, Box<Node<'a>>),
+    Mod(Box<Node<'a>>, Box<Node<'a>>),
     Pow(Box<Node<'a>>, Box<Node<'a>>),
+    BitAnd(Box<Node<'a>>, Box<Node<'a>>),
+    BitOr(Box<Node<'a>>, Box<Node<'a>>),
+    Shl(Box<Node<'a>>, Box<Node<'a>>),
+    Shr(Box<Node<'a>>, Box<Node<'a>>),
 
     UnryNot(Box<Node<'a>>),
     UnrySub(Box<Node<'a>>),
+    Percent(Box<Node<'a>>),
+    Abs(Box<Node<'a>>),
 
     Unit(&'a str),
     Num(NumType),
+    FnCall {
+        name: &'a str,
+        args: Vec<Node<'a>>,
+    },
 
     Assign(&'a str, Box<Node<'a>>),
     AddAssign(&'a str, Box<Node<'a>>),
     SubAssign(&'a str, Box<Node<'a>>),
     MulAssign(&'a str, Box<Node<'a>>),
     DivAssign(&'a str, Box<Node<'a>>),
+    PowAssign(&'a str, Box<Node<'a>>),
 
     Equal(Box<Node<'a>>, Box<Node<'a>>),
     NeEqual(Box<Node<'a>>, Box<Node<'a>>),
@@ -165,16 +393,177 @@ pub enum NodeType<'a> {
     Lesser(Box<Node<'a>>, Box<Node<'a>>),
 
     Scope(Vec<Node<'a>>),
+    List(Vec<Node<'a>>),
+    Convert {
+        expr: Box<Node<'a>>,
+        target: Unit<'a>,
+    },
 
     ParseError,
 }
 
 impl<'a> NodeType<'a> {
     pub fn if_else(cond: Box<Node<'a>>, if_body: Box<Node<'a>>, else_body: Option<Box<Node<'a>>>) -> Self {
-        NodeType::IfElse { cond, ifBody: if_body, elseBody: else_body }
+        NodeType::IfElse { cond, if_body, else_body }
+    }
+}
+
+// reads units in the form Display writes them, as in `[kg m/s^2]` or
+// `[1/(s A)]`, the brackets are optional and a '/' divides by the next
+// atom or group only
+struct UnitParser<'a> {
+    src: &'a str,
+    tokens: std::iter::Peekable<logos::SpannedIter<'a, Token<'a>>>,
+}
+
+impl<'a> UnitParser<'a> {
+    fn unexpected(&self, tok: Result<Token<'a>, LexError>, span: Range<usize>) -> MorphError<'a> {
+        match tok {
+            Ok(tok) => MorphError::custom(
+                span,
+                format!("unexpected '{}' in quantity literal", tok),
+                ErrorType::UndefinedSyntax,
+            ),
+            Err(err) => MorphError::custom(
+                span.clone(),
+                format!("{}: {}", err, &self.src[span]),
+                ErrorType::CouldNotLex,
+            ),
+        }
+    }
+
+    fn eat(&mut self, tok: Token<'a>) -> Option<Range<usize>> {
+        self.tokens
+            .next_if(|(t, _)| t == &Ok(tok.clone()))
+            .map(|(_, span)| span)
+    }
+
+    // the whole remaining input
+    fn unit(&mut self) -> Result<Unit<'a>, MorphError<'a>> {
+        let unit = match self.eat(Token::LBracket) {
+            Some(open) => {
+                let unit = match self.eat(Token::RBracket) {
+                    Some(_) => return self.finish(Unit::none()),
+                    None => self.product()?,
+                };
+                self.close(Token::RBracket, open)?;
+                unit
+            }
+            None if self.tokens.peek().is_none() => Unit::none(),
+            None => self.product()?,
+        };
+
+        self.finish(unit)
+    }
+
+    fn finish(&mut self, unit: Unit<'a>) -> Result<Unit<'a>, MorphError<'a>> {
+        match self.tokens.next() {
+            Some((tok, span)) => Err(self.unexpected(tok, span)),
+            None => Ok(unit),
+        }
+    }
+
+    fn close(&mut self, tok: Token<'a>, open: Range<usize>) -> Result<(), MorphError<'a>> {
+        match self.tokens.next() {
+            Some((Ok(t), _)) if t == tok => Ok(()),
+            Some((t, span)) => Err(self.unexpected(t, span)),
+            None => Err(MorphError::custom(
+                open,
+                format!("expected a closing '{}'", tok),
+                ErrorType::UndefinedSyntax,
+            )),
+        }
+    }
+
+    fn product(&mut self) -> Result<Unit<'a>, MorphError<'a>> {
+        let mut unit = match self.eat(Token::Num(dec!(1))) {
+            Some(span) => match self.tokens.peek() {
+                Some((Ok(Token::Div), _)) => Unit::none(),
+                _ => return Err(self.unexpected(Ok(Token::Num(dec!(1))), span)),
+            },
+            None => self.term()?,
+        };
+
+        loop {
+            let op = match self.tokens.peek() {
+                Some((Ok(Token::Mul | Token::Div), _)) => self.tokens.next(),
+                Some((Ok(Token::Unit(_) | Token::LParen), _)) => None,
+                _ => return Ok(unit),
+            };
+
+            let rhs = match (&op, self.tokens.peek()) {
+                (Some((tok, span)), None) => {
+                    return Err(MorphError::custom(
+                        span.clone(),
+                        format!("expected a unit after '{}'", tok.clone().unwrap()),
+                        ErrorType::UndefinedSyntax,
+                    ))
+                }
+                _ => self.term()?,
+            };
+
+            unit = match op {
+                Some((Ok(Token::Div), _)) => unit / rhs,
+                _ => unit * rhs,
+            };
+        }
+    }
+
+    // an atom with an optional exponent or a parenthesised product
+    fn term(&mut self) -> Result<Unit<'a>, MorphError<'a>> {
+        let (tok, span) = match self.tokens.next() {
+            Some(next) => next,
+            None => {
+                let end = self.src.len()..self.src.len();
+                return Err(MorphError::custom(
+                    end,
+                    "expected a unit",
+                    ErrorType::UndefinedSyntax,
+                ));
+            }
+        };
+
+        let ident = match tok {
+            Ok(Token::Unit(ident)) => ident,
+            Ok(Token::LParen) => {
+                let unit = self.product()?;
+                self.close(Token::RParen, span)?;
+                return Ok(unit);
+            }
+            tok => return Err(self.unexpected(tok, span)),
+        };
+
+        let mut exp = dec!(1);
+        if let Some(pow_span) = self.eat(Token::Pow) {
+            if self.eat(Token::Sub).is_some() {
+                exp = dec!(-1);
+            }
+            match self.tokens.next() {
+                Some((Ok(Token::Num(num)), _)) => exp *= num,
+                Some((tok, span)) => return Err(self.unexpected(tok, span)),
+                None => {
+                    return Err(MorphError::custom(
+                        pow_span,
+                        "expected an exponent after '^'",
+                        ErrorType::UndefinedSyntax,
+                    ))
+                }
+            }
+        }
+
+        Ok(Unit::from(UnitAtom {
+            name: ident.name,
+            exp,
+            span: None,
+        }))
     }
 }
 
+// strips insignificant trailing zeros, 5.10 is shown as 5.1 and 0.0 as 0
+pub fn format_number(d: &Decimal) -> String {
+    d.normalize().to_string()
+}
+
 pub fn merge_span(r1: &Range<usize>, r2: &Range<usize>) -> Range<usize> {
     let mut smaller = r1;
     let mut bigger = r2;
@@ -186,6 +575,38 @@ pub fn merge_span(r1: &Range<usize>, r2: &Range<usize>) -> Range<usize> {
     smaller.start..bigger.end
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SourcePos {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, col {}", self.line, self.col)
+    }
+}
+
+// lines and columns start at 1, a '\n' belongs to the line it terminates
+pub fn byte_to_pos(src: &str, byte: usize) -> SourcePos {
+    let mut pos = SourcePos { line: 1, col: 1 };
+
+    for (i, c) in src.char_indices() {
+        if i >= byte {
+            break;
+        }
+
+        if c == '\n' {
+            pos.line += 1;
+            pos.col = 1;
+        } else {
+            pos.col += 1;
+        }
+    }
+
+    pos
+}
+
 #[derive(Debug, Clone)]
 pub struct Node<'a> {
     pub typ: NodeType<'a>,
@@ -217,18 +638,111 @@ impl<'a> Node<'a> {
         }
     }
 
+    // the number of nodes on the longest path down to a leaf, a leaf has depth 1
+    pub fn depth(&self) -> usize {
+        self.levels().map(|(level, _)| level + 1).max().unwrap_or(1)
+    }
+
+    // every node of the tree in pre-order with its distance from this one. The
+    // walk keeps its own stack, so it is safe on trees of any depth
+    pub fn levels(&self) -> impl Iterator<Item = (usize, &Node<'a>)> {
+        let mut stack = vec![(0, self)];
+
+        std::iter::from_fn(move || {
+            let (level, node) = stack.pop()?;
+            let children = node.children().into_iter().rev();
+            stack.extend(children.map(|child| (level + 1, child)));
+            Some((level, node))
+        })
+    }
+
+    // the number of nodes in the tree, including this one
+    pub fn size(&self) -> usize {
+        1 + self.children().into_iter().map(Node::size).sum::<usize>()
+    }
+
+    // the immediate subnodes in source order, leaves have none
+    pub fn children(&self) -> Vec<&Node<'a>> {
+        use NodeType::*;
+
+        match &self.typ {
+            Def(_) | Unit(_) | Num(_) | ParseError => vec![],
+            DefFn { body, .. } => vec![body],
+            IfElse {
+                cond,
+                if_body,
+                else_body,
+            } => [cond, if_body]
+                .into_iter()
+                .chain(else_body)
+                .map(|n| &**n)
+                .collect(),
+
+            Add(lhs, rhs)
+            | Sub(lhs, rhs)
+            | Mul(lhs, rhs)
+            | Div(lhs, rhs)
+            | Mod(lhs, rhs)
+            | Pow(lhs, rhs)
+            | BitAnd(lhs, rhs)
+            | BitOr(lhs, rhs)
+            | Shl(lhs, rhs)
+            | Shr(lhs, rhs)
+            | Equal(lhs, rhs)
+            | NeEqual(lhs, rhs)
+            | GreaterEqual(lhs, rhs)
+            | LesserEqual(lhs, rhs)
+            | Greater(lhs, rhs)
+            | Lesser(lhs, rhs) => vec![lhs, rhs],
+
+            UnryNot(val) | UnrySub(val) | Percent(val) | Abs(val) => vec![val],
+            Convert { expr, .. } => vec![expr],
+
+            Assign(_, val)
+            | AddAssign(_, val)
+            | SubAssign(_, val)
+            | MulAssign(_, val)
+            | DivAssign(_, val)
+            | PowAssign(_, val) => vec![val],
+
+            FnCall { args: nodes, .. } | Scope(nodes) | List(nodes) => nodes.iter().collect(),
+        }
+    }
+
+    pub fn is_err(&self) -> bool {
+        self.typ == NodeType::ParseError
+    }
+
+    pub fn span_start_pos(&self, src: &str) -> SourcePos {
+        byte_to_pos(src, self.span.start)
+    }
+
     #[allow(dead_code)]
-    pub fn assign(&mut self, other: Node<'a>) {
-        if let NodeType::Unit(name) = self.typ {
-            let range = merge_span(&self.span, &other.span);
-            let typ = NodeType::Assign(name, other.into());
-            *self = Node { typ, span: range };
-        } else {
-            panic!("You can only Assign to the Node::Unit enum");
+    pub fn assign(&mut self, other: Node<'a>) -> Result<(), MorphError<'a>> {
+        self.assign_with(NodeType::Assign, other)
+    }
+
+    // only identifiers are assignable, anything else is reported at its span
+    // and leaves `self` as it was
+    pub fn assign_with(
+        &mut self,
+        ctor: AssignCtor<'a>,
+        other: Node<'a>,
+    ) -> Result<(), MorphError<'a>> {
+        match self.typ {
+            NodeType::Unit(name) => {
+                let span = merge_span(&self.span, &other.span);
+                *self = Node::new(ctor(name, other.into()), span);
+                Ok(())
+            }
+            _ => Err(MorphError::custom(
+                self.span.clone(),
+                format!("can't assign to '{}'", self.display_minimal()),
+                ErrorType::InvalidAssignTarget,
+            )),
         }
     }
 
-    wrap_binop_node!(binop: equal -> Equal);
     wrap_binop_node!(binop: mul -> Mul);
     wrap_binop_node!(binop: div -> Div);
     wrap_binop_node!(binop: add -> Add);
@@ -244,55 +758,371 @@ impl<'a> From<Node<'a>> for NodeType<'a> {
 
 impl<'a> fmt::Display for Node<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+// a node rendered inside a scope nested `depth` levels deep
+struct Indented<'a, 'b>(&'b Node<'a>, usize);
+
+impl fmt::Display for Indented<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_indented(f, self.1)
+    }
+}
+
+impl<'a> Node<'a> {
+    fn nested(&self, depth: usize) -> Indented<'a, '_> {
+        Indented(self, depth)
+    }
+
+    // the body of a scope is indented by two spaces per enclosing scope, the
+    // braces line up with the line they open on
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
         use NodeType::*;
 
         match &self.typ {
             Def(name) => write!(f, "(def {})", name),
-            IfElse { cond, ifBody, elseBody} => {
-                write!(f, "if {} {} ", cond, ifBody)?;
-                if let Some(body) = elseBody {
-                    write!(f, "else {}", body)?;
+            DefFn { name, params, body } => {
+                write!(
+                    f,
+                    "(def {}({}) {})",
+                    name,
+                    params.join(", "),
+                    body.nested(depth)
+                )
+            }
+            IfElse {
+                cond,
+                if_body,
+                else_body,
+            } => {
+                write!(f, "if {} {}", cond.nested(depth), if_body.nested(depth))?;
+                if let Some(body) = else_body {
+                    write!(f, " else {}", body.nested(depth))?;
                 }
                 Ok(())
-            },
-            Add(left, right) => write!(f, "({} + {})", left, right),
-            Sub(left, right) => write!(f, "({} - {})", left, right),
-            Mul(left, right) => write!(f, "({} * {})", left, right),
-            Div(left, right) => write!(f, "({} / {})", left, right),
-            Pow(left, right) => write!(f, "({} ^ {})", left, right),
-            UnryNot(val) => write!(f, "!({})", val),
-            UnrySub(val) => write!(f, "-({})", val),
+            }
+            Add(left, right) => write!(f, "({} + {})", left.nested(depth), right.nested(depth)),
+            Sub(left, right) => write!(f, "({} - {})", left.nested(depth), right.nested(depth)),
+            Mul(left, right) => write!(f, "({} * {})", left.nested(depth), right.nested(depth)),
+            Div(left, right) => write!(f, "({} / {})", left.nested(depth), right.nested(depth)),
+            Mod(left, right) => write!(f, "({} mod {})", left.nested(depth), right.nested(depth)),
+            Pow(left, right) => write!(f, "({} ^ {})", left.nested(depth), right.nested(depth)),
+            BitAnd(left, right) => write!(f, "({} & {})", left.nested(depth), right.nested(depth)),
+            BitOr(left, right) => write!(f, "({} | {})", left.nested(depth), right.nested(depth)),
+            Shl(left, right) => write!(f, "({} << {})", left.nested(depth), right.nested(depth)),
+            Shr(left, right) => write!(f, "({} >> {})", left.nested(depth), right.nested(depth)),
+            UnryNot(val) => write!(f, "!({})", val.nested(depth)),
+            UnrySub(val) => write!(f, "-({})", val.nested(depth)),
+            Percent(val) => write!(f, "({}%)", val.nested(depth)),
+            Abs(val) => write!(f, "|{}|", val.nested(depth)),
             Unit(unit) => write!(f, "{}", unit),
-            Num(num_type) => write!(f, "{}", num_type),
-            Assign(name, val) => write!(f, "({} = {})", name, val),
-            AddAssign(name, val) => write!(f, "({} += {})", name, val),
-            SubAssign(name, val) => write!(f, "({} -= {})", name, val),
-            MulAssign(name, val) => write!(f, "({} *= {})", name, val),
-            DivAssign(name, val) => write!(f, "({} /= {})", name, val),
-            Equal(lhs, rhs) => write!(f, "({} == {})", lhs, rhs),
-            NeEqual(lhs, rhs) => write!(f, "({} != {})", lhs, rhs),
-            GreaterEqual(lhs, rhs) => write!(f, "({} >= {})", lhs, rhs),
-            LesserEqual(lhs, rhs) => write!(f, "({} <= {})", lhs, rhs),
-            Greater(lhs, rhs) => write!(f, "({} > {})", lhs, rhs),
-            Lesser(lhs, rhs) => write!(f, "({} < {})", lhs, rhs),
+            Num(num_type) => write!(f, "{}", format_number(num_type)),
+            FnCall { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg.nested(depth))?;
+                }
+                write!(f, ")")
+            }
+            Assign(name, val) => write!(f, "({} = {})", name, val.nested(depth)),
+            AddAssign(name, val) => write!(f, "({} += {})", name, val.nested(depth)),
+            SubAssign(name, val) => write!(f, "({} -= {})", name, val.nested(depth)),
+            MulAssign(name, val) => write!(f, "({} *= {})", name, val.nested(depth)),
+            DivAssign(name, val) => write!(f, "({} /= {})", name, val.nested(depth)),
+            PowAssign(name, val) => write!(f, "({} ^= {})", name, val.nested(depth)),
+            Equal(lhs, rhs) => write!(f, "({} == {})", lhs.nested(depth), rhs.nested(depth)),
+            NeEqual(lhs, rhs) => {
+                write!(f, "({} != {})", lhs.nested(depth), rhs.nested(depth))
+            }
+            GreaterEqual(lhs, rhs) => {
+                write!(f, "({} >= {})", lhs.nested(depth), rhs.nested(depth))
+            }
+            LesserEqual(lhs, rhs) => {
+                write!(f, "({} <= {})", lhs.nested(depth), rhs.nested(depth))
+            }
+            Greater(lhs, rhs) => write!(f, "({} > {})", lhs.nested(depth), rhs.nested(depth)),
+            Lesser(lhs, rhs) => write!(f, "({} < {})", lhs.nested(depth), rhs.nested(depth)),
             Scope(nodes) => {
                 writeln!(f, "{{")?;
                 for n in nodes {
-                    writeln!(f, "{}", n)?;
+                    writeln!(f, "{}{}", "  ".repeat(depth), n.nested(depth + 1))?;
                 }
-                write!(f, "}}")
+                write!(f, "{}}}", "  ".repeat(depth.saturating_sub(1)))
+            }
+            List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item.nested(depth))?;
+                }
+                write!(f, "]")
+            }
+            Convert { expr, target } => {
+                write!(f, "({} : {})", expr.nested(depth), target.display_target())
             }
             ParseError => write!(f, "Error"),
         }
     }
 }
 
+impl<'a> NodeType<'a> {
+    // the position and name of the variant, for serde and for errors that
+    // shouldn't render a whole subtree
+    pub fn variant(&self) -> (u32, &'static str) {
+        use NodeType::*;
+
+        match self {
+            Def(..) => (0, "Def"),
+            DefFn { .. } => (1, "DefFn"),
+            IfElse { .. } => (2, "IfElse"),
+            Add(..) => (3, "Add"),
+            Sub(..) => (4, "Sub"),
+            Mul(..) => (5, "Mul"),
+            Div(..) => (6, "Div"),
+            Mod(..) => (7, "Mod"),
+            Pow(..) => (8, "Pow"),
+            BitAnd(..) => (9, "BitAnd"),
+            BitOr(..) => (10, "BitOr"),
+            Shl(..) => (11, "Shl"),
+            Shr(..) => (12, "Shr"),
+            UnryNot(..) => (13, "UnryNot"),
+            UnrySub(..) => (14, "UnrySub"),
+            Percent(..) => (15, "Percent"),
+            Abs(..) => (16, "Abs"),
+            Unit(..) => (17, "Unit"),
+            Num(..) => (18, "Num"),
+            FnCall { .. } => (19, "FnCall"),
+            Assign(..) => (20, "Assign"),
+            AddAssign(..) => (21, "AddAssign"),
+            SubAssign(..) => (22, "SubAssign"),
+            MulAssign(..) => (23, "MulAssign"),
+            DivAssign(..) => (24, "DivAssign"),
+            PowAssign(..) => (25, "PowAssign"),
+            Equal(..) => (26, "Equal"),
+            NeEqual(..) => (27, "NeEqual"),
+            GreaterEqual(..) => (28, "GreaterEqual"),
+            LesserEqual(..) => (29, "LesserEqual"),
+            Greater(..) => (30, "Greater"),
+            Lesser(..) => (31, "Lesser"),
+            Scope(..) => (32, "Scope"),
+            List(..) => (33, "List"),
+            Convert { .. } => (34, "Convert"),
+            ParseError => (35, "ParseError"),
+        }
+    }
+
+    // the token of a binary or assignment operator
+    pub fn operator(&self) -> Option<Token<'static>> {
+        use NodeType::*;
+
+        let tok = match self {
+            Equal(..) => Token::Equal,
+            NeEqual(..) => Token::NeEqual,
+            GreaterEqual(..) => Token::GreaterEqual,
+            LesserEqual(..) => Token::LesserEqual,
+            Greater(..) => Token::Greater,
+            Lesser(..) => Token::Lesser,
+            Assign(..) => Token::Assign,
+            AddAssign(..) => Token::AddAssign,
+            SubAssign(..) => Token::SubAssign,
+            MulAssign(..) => Token::MulAssign,
+            DivAssign(..) => Token::DivAssign,
+            PowAssign(..) => Token::PowAssign,
+            Add(..) => Token::Add,
+            Sub(..) => Token::Sub,
+            Mul(..) => Token::Mul,
+            Div(..) => Token::Div,
+            Mod(..) => Token::Mod,
+            Pow(..) => Token::Pow,
+            BitAnd(..) => Token::Amp,
+            BitOr(..) => Token::Bar,
+            Shl(..) => Token::Shl,
+            Shr(..) => Token::Shr,
+            _ => return None,
+        };
+
+        Some(tok)
+    }
+
+    // binding strength as an operand, operators take theirs from the parser
+    fn precedence(&self) -> (u8, Assoc) {
+        use NodeType::*;
+
+        match self {
+            IfElse { .. } | Convert { .. } => (0, Assoc::Left),
+            UnryNot(_) | UnrySub(_) => (PREFIX_PRECEDENCE, Assoc::Right),
+            Percent(_) => (POSTFIX_PRECEDENCE, Assoc::Left),
+            typ => typ
+                .operator()
+                .and_then(|op| precedence(&op))
+                .unwrap_or((u8::MAX, Assoc::Left)),
+        }
+    }
+}
+
+/// Displays a node with only the parentheses needed to parse it back the same way.
+pub struct DisplayMinimal<'a, 'b>(&'b Node<'a>);
+
+impl<'a> Node<'a> {
+    pub fn display_minimal(&self) -> DisplayMinimal<'a, '_> {
+        DisplayMinimal(self)
+    }
+}
+
+impl fmt::Display for DisplayMinimal<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use NodeType::*;
+
+        fn operand(f: &mut fmt::Formatter<'_>, node: &Node<'_>, min_prec: u8) -> fmt::Result {
+            if node.typ.precedence().0 < min_prec {
+                write!(f, "({})", DisplayMinimal(node))
+            } else {
+                write!(f, "{}", DisplayMinimal(node))
+            }
+        }
+
+        let op = self.0.typ.operator();
+        let (prec, assoc) = self.0.typ.precedence();
+
+        let binop = |f: &mut fmt::Formatter<'_>, lhs: &Node<'_>, rhs: &Node<'_>| {
+            let (lhs_prec, rhs_prec) = match assoc {
+                Assoc::Left => (prec, prec + 1),
+                Assoc::Right => (prec + 1, prec),
+            };
+
+            operand(f, lhs, lhs_prec)?;
+            write!(f, " {} ", op.as_ref().unwrap())?;
+            operand(f, rhs, rhs_prec)
+        };
+
+        // the assigned value can't be a comparison
+        let assign = |f: &mut fmt::Formatter<'_>, name: &str, val: &Node<'_>| {
+            write!(f, "{} {} ", name, op.as_ref().unwrap())?;
+            operand(f, val, prec + 1)
+        };
+
+        match &self.0.typ {
+            Def(name) => write!(f, "def {}", name),
+            DefFn { name, params, body } => {
+                write!(
+                    f,
+                    "def {}({}) {}",
+                    name,
+                    params.join(", "),
+                    DisplayMinimal(body)
+                )
+            }
+            IfElse {
+                cond,
+                if_body,
+                else_body,
+            } => {
+                write!(f, "if {} {}", DisplayMinimal(cond), DisplayMinimal(if_body))?;
+                if let Some(body) = else_body {
+                    write!(f, " else {}", DisplayMinimal(body))?;
+                }
+                Ok(())
+            }
+            Add(lhs, rhs)
+            | Sub(lhs, rhs)
+            | Mul(lhs, rhs)
+            | Div(lhs, rhs)
+            | Mod(lhs, rhs)
+            | Pow(lhs, rhs)
+            | Equal(lhs, rhs)
+            | NeEqual(lhs, rhs)
+            | GreaterEqual(lhs, rhs)
+            | LesserEqual(lhs, rhs)
+            | Greater(lhs, rhs)
+            | Lesser(lhs, rhs)
+            | BitAnd(lhs, rhs)
+            | Shl(lhs, rhs)
+            | Shr(lhs, rhs) => binop(f, lhs, rhs),
+            // a '|' followed by a sign or bar would close an abs instead
+            BitOr(lhs, rhs) => match DisplayMinimal(rhs).to_string() {
+                text if text.starts_with(['-', '+', '!', '|']) => {
+                    operand(f, lhs, prec)?;
+                    write!(f, " | ({})", text)
+                }
+                _ => binop(f, lhs, rhs),
+            },
+            UnryNot(val) => {
+                write!(f, "!")?;
+                operand(f, val, prec)
+            }
+            UnrySub(val) => {
+                write!(f, "-")?;
+                operand(f, val, prec)
+            }
+            Percent(val) => {
+                operand(f, val, prec + 1)?;
+                write!(f, "%")
+            }
+            Abs(val) => write!(f, "|{}|", DisplayMinimal(val)),
+            Assign(name, val)
+            | AddAssign(name, val)
+            | SubAssign(name, val)
+            | MulAssign(name, val)
+            | DivAssign(name, val)
+            | PowAssign(name, val) => assign(f, name, val),
+            FnCall { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", DisplayMinimal(arg))?;
+                }
+                write!(f, ")")
+            }
+            Scope(nodes) => {
+                writeln!(f, "{{")?;
+                for n in nodes {
+                    writeln!(f, "{}", DisplayMinimal(n))?;
+                }
+                write!(f, "}}")
+            }
+            List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", DisplayMinimal(item))?;
+                }
+                write!(f, "]")
+            }
+            Convert { expr, target } => {
+                operand(f, expr, 1)?;
+                write!(f, " : {}", target.display_target())
+            }
+            Unit(_) | Num(_) | ParseError => write!(f, "{}", self.0),
+        }
+    }
+}
+
 impl<'a> cmp::PartialEq for Node<'a> {
     fn eq(&self, other: &Self) -> bool {
         self.typ == other.typ
     }
 }
 
+impl<'a> cmp::Eq for Node<'a> {}
+
+// the span is ignored, like in PartialEq
+impl<'a> Hash for Node<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.typ.hash(state);
+    }
+}
+
 macro_rules! impl_node_op {
 
     (binop: $op: ident -> $n_op: ident) => {
@@ -313,44 +1143,130 @@ macro_rules! impl_node_op {
         impl_node_op!(binop: $op -> $op);
     };
 
-    (assign: $op: ident) => {
-        impl<'a> std::ops::$op<Node<'a>> for Node<'a> {
+    (unop: $op: ident -> $n_op: ident) => {
+        impl<'a> std::ops::$op for Node<'a> {
+            type Output = Node<'a>;
+
             paste! {
-                fn [<$op:snake>](&mut self, other: Self) {
-                    // Self::Output::$op(self.into(), rhs.into())
-                    if let NodeType::Unit(name) = self.typ {
-                        let span = merge_span(&self.span, &other.span);
-                        let typ = NodeType::$op(name, other.into());
-                        *self = Node {typ, span}
-                    } else {
-                        panic!("You can only {} to the Node::Unit enum", stringify!($op))
-                    }
+                fn [<$op:lower>](self) -> Self::Output {
+                    let span = self.span.clone();
+                    let typ = NodeType::$n_op(self.into());
+                    Node {typ, span}
+                }
+            }
+        }
+    };
+
+    (assign: $op: ident :: $fn_name: ident -> $n_op: ident) => {
+        impl<'a> std::ops::$op<Node<'a>> for Node<'a> {
+            // an invalid target turns the whole assignment into an error node
+            fn $fn_name(&mut self, other: Self) {
+                let span = merge_span(&self.span, &other.span);
+                if self.assign_with(NodeType::$n_op, other).is_err() {
+                    *self = Node::err(span);
                 }
             }
         }
     };
+
+    (assign: $op: ident) => {
+        paste! {
+            impl_node_op!(assign: $op::[<$op:snake>] -> $op);
+        }
+    };
 }
 
+pub type AssignCtor<'a> = fn(&'a str, Box<Node<'a>>) -> NodeType<'a>;
+
 impl_node_op!(binop: Mul);
 impl_node_op!(binop: Div);
 impl_node_op!(binop: Add);
 impl_node_op!(binop: Sub);
+impl_node_op!(binop: Rem -> Mod);
 impl_node_op!(binop: BitXor -> Pow);
 
+impl_node_op!(unop: Neg -> UnrySub);
+impl_node_op!(unop: Not -> UnryNot);
+
 impl_node_op!(assign: AddAssign);
 impl_node_op!(assign: SubAssign);
 impl_node_op!(assign: MulAssign);
 impl_node_op!(assign: DivAssign);
+impl_node_op!(assign: BitXorAssign::bitxor_assign -> PowAssign);
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+// extracts the payload of a literal node, anything else is a TypeError
+macro_rules! impl_try_from_node {
+    ($variant: ident -> $t: ty, $desc: literal) => {
+        impl<'a> TryFrom<&Node<'a>> for $t {
+            type Error = MorphError<'a>;
+
+            fn try_from(node: &Node<'a>) -> Result<Self, Self::Error> {
+                match node.typ {
+                    NodeType::$variant(val) => Ok(val),
+                    _ => Err(MorphError::custom(
+                        node.span.clone(),
+                        format!("expected {}, found: {}", $desc, node.typ.variant().1),
+                        ErrorType::TypeError,
+                    )),
+                }
+            }
+        }
+    };
+}
+
+impl_try_from_node!(Num -> Decimal, "a number literal");
+impl_try_from_node!(Unit -> &'a str, "a unit");
+
+// the span points at the token the atom was written as, it is not part of
+// the atom's identity and only used to report errors
+#[derive(Debug, Clone)]
 pub struct UnitAtom<'a> {
     name: &'a str,
     exp: Decimal,
+    span: Option<Range<usize>>,
+}
+
+impl<'a> PartialEq for UnitAtom<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.exp == other.exp
+    }
+}
+
+impl<'a> Eq for UnitAtom<'a> {}
+
+impl<'a> std::hash::Hash for UnitAtom<'a> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.exp.hash(state);
+    }
 }
 
 impl<'a> UnitAtom<'a> {
     pub fn base(name: &'a str) -> Self {
-        Self { name, exp: dec!(1) }
+        Self {
+            name,
+            exp: dec!(1),
+            span: None,
+        }
+    }
+
+    pub fn with_span(self, span: Range<usize>) -> Self {
+        Self {
+            span: Some(span),
+            ..self
+        }
+    }
+
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.span.clone()
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn exp(&self) -> Decimal {
+        self.exp
     }
 }
 
@@ -359,12 +1275,13 @@ impl<'a> fmt::Display for UnitAtom<'a> {
         if self.exp.is_integer() && self.exp == dec!(1) {
             write!(f, "{}", self.name)
         } else {
-            write!(f, "{}^{}", self.name, self.exp)
+            write!(f, "{}^{}", self.name, format_number(&self.exp))
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// the default unit is dimensionless
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
 pub struct Unit<'a>(Vec<UnitAtom<'a>>);
 
 impl<'a> Unit<'a> {
@@ -372,58 +1289,59 @@ impl<'a> Unit<'a> {
         Unit(vec![])
     }
 
+    // the inverse of Display, `[kg m/s^2]` parses back to the same unit
+    pub fn parse(src: &'a str) -> Result<Unit<'a>, MorphError<'a>> {
+        let tokens = Token::lexer(src).spanned().peekable();
+        UnitParser { src, tokens }.unit()
+    }
+
     pub fn has_units(&self) -> bool {
         !self.0.is_empty()
     }
 
-    pub fn pow(mut self, exp: Decimal) -> Self {
-        for u in &mut self.0 {
-            u.exp *= exp;
-        }
-        self
+    pub fn is_dimensionless(&self) -> bool {
+        self.0.is_empty()
     }
-}
 
-impl<'a> From<UnitAtom<'a>> for Unit<'a> {
-    fn from(value: UnitAtom<'a>) -> Self {
-        Unit(vec![value])
+    pub fn atoms(&self) -> impl Iterator<Item = &UnitAtom<'a>> {
+        self.0.iter()
     }
-}
-
-impl<'a> fmt::Display for Unit<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.0.is_empty() {
-            return Ok(());
-        }
-
-        write!(f, "[")?;
-
-        if let Some((last, rest)) = self.0.split_last() {
-            for u in rest {
-                write!(f, "{} ", u)?;
-            }
-
-            write!(f, "{}]", last)?;
-        }
 
-        Ok(())
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
-}
 
-impl<'a> ops::Mul for Unit<'a> {
-    type Output = Unit<'a>;
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        let mut merged: Vec<_> = Vec::new();
+    pub fn same_dimension(&self, other: &Unit<'a>) -> bool {
+        self.clone().normalized() == other.clone().normalized()
+    }
 
-        for u in self.0 {
-            merged.push(u);
-        }
+    pub fn with_span(self, span: Range<usize>) -> Unit<'a> {
+        Unit(
+            self.0
+                .into_iter()
+                .map(|u| u.with_span(span.clone()))
+                .collect(),
+        )
+    }
 
-        for u in rhs.0 {
-            merged.push(u);
-        }
+    // the span of the first atom of `self` that `other` has no match for
+    pub fn mismatch_span(&self, other: &Unit<'a>) -> Option<Range<usize>> {
+        let other = other.clone().normalized();
+        self.clone()
+            .normalized()
+            .0
+            .into_iter()
+            .filter(|u| !other.0.contains(u))
+            .find_map(|u| u.span)
+    }
 
+    // the canonical form: duplicates merged and zero exponents dropped
+    pub fn normalized(self) -> Unit<'a> {
+        let mut merged = self.0;
         merged.sort_by(|a, b| a.name.cmp(b.name));
 
         let mut current: Option<UnitAtom> = None;
@@ -449,13 +1367,112 @@ impl<'a> ops::Mul for Unit<'a> {
         }
 
         result.retain(|unit| !unit.exp.is_zero());
-        result.sort_by(|a, _| match a.exp.is_sign_negative() {
-            true => cmp::Ordering::Greater,
-            false => cmp::Ordering::Less,
+        // positive exponents first, each group ordered by name
+        result.sort_by(|a, b| {
+            a.exp
+                .is_sign_negative()
+                .cmp(&b.exp.is_sign_negative())
+                .then(a.name.cmp(b.name))
         });
 
         Unit(result)
     }
+
+    // the form a conversion target is written in, as in `x : km * h^-1`
+    pub fn display_target(&self) -> String {
+        let atoms: Vec<_> = self.0.iter().map(|u| u.to_string()).collect();
+        atoms.join(" * ")
+    }
+
+    pub fn reciprocal(&self) -> Unit<'a> {
+        self.clone().pow(dec!(-1))
+    }
+
+    pub fn pow(mut self, exp: Decimal) -> Self {
+        for u in &mut self.0 {
+            u.exp *= exp;
+        }
+        self.normalized()
+    }
+}
+
+impl<'a> From<UnitAtom<'a>> for Unit<'a> {
+    fn from(value: UnitAtom<'a>) -> Self {
+        Unit(vec![value])
+    }
+}
+
+impl<'a> fmt::Display for Unit<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn write_atoms(f: &mut fmt::Formatter<'_>, atoms: &[UnitAtom]) -> fmt::Result {
+            if let Some((last, rest)) = atoms.split_last() {
+                for u in rest {
+                    write!(f, "{} ", u)?;
+                }
+
+                write!(f, "{}", last)?;
+            }
+
+            Ok(())
+        }
+
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let (num, den): (Vec<UnitAtom>, Vec<UnitAtom>) = self
+            .0
+            .iter()
+            .cloned()
+            .partition(|u| u.exp.is_sign_positive());
+        let den: Vec<_> = den
+            .into_iter()
+            .map(|u| UnitAtom { exp: -u.exp, ..u })
+            .collect();
+
+        write!(f, "[")?;
+
+        if num.is_empty() {
+            write!(f, "1")?;
+        } else {
+            write_atoms(f, &num)?;
+        }
+
+        match den.len() {
+            0 => {}
+            1 => write!(f, "/{}", den[0])?,
+            _ => {
+                write!(f, "/(")?;
+                write_atoms(f, &den)?;
+                write!(f, ")")?;
+            }
+        }
+
+        write!(f, "]")
+    }
+}
+
+// both collect into the canonical form, as if the atoms were multiplied
+impl<'a> FromIterator<UnitAtom<'a>> for Unit<'a> {
+    fn from_iter<I: IntoIterator<Item = UnitAtom<'a>>>(iter: I) -> Self {
+        Unit(iter.into_iter().collect()).normalized()
+    }
+}
+
+impl<'a> Extend<UnitAtom<'a>> for Unit<'a> {
+    fn extend<I: IntoIterator<Item = UnitAtom<'a>>>(&mut self, iter: I) {
+        self.0.extend(iter);
+        *self = std::mem::take(&mut self.0).into_iter().collect();
+    }
+}
+
+impl<'a> ops::Mul for Unit<'a> {
+    type Output = Unit<'a>;
+
+    fn mul(mut self, rhs: Self) -> Self::Output {
+        self.extend(rhs.0);
+        self
+    }
 }
 
 #[allow(clippy::suspicious_arithmetic_impl)]
@@ -463,14 +1480,86 @@ impl<'a> ops::Div for Unit<'a> {
     type Output = Unit<'a>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        let mut res = rhs.clone();
-        res.0.iter_mut().for_each(|u| u.exp *= dec!(-1));
+        self * rhs.reciprocal()
+    }
+}
 
-        self * res
+// integer exponents are exact, by repeated squaring, fractional ones go
+// through f64 and keep only about 15 significant digits
+pub fn checked_pow(base: Decimal, exp: Decimal) -> Option<Decimal> {
+    match exp.to_i64() {
+        Some(e) if exp.is_integer() => checked_powi(base, e),
+        _ => Decimal::from_f64(base.to_f64()?.powf(exp.to_f64()?)),
     }
 }
 
-#[derive(Debug, Clone)]
+fn checked_powi(mut base: Decimal, exp: i64) -> Option<Decimal> {
+    let mut acc = dec!(1);
+    let mut e = exp.unsigned_abs();
+
+    while e > 0 {
+        if e & 1 == 1 {
+            acc = acc.checked_mul(base)?;
+        }
+        e >>= 1;
+        if e > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+
+    match exp < 0 {
+        true => dec!(1).checked_div(acc),
+        false => Some(acc),
+    }
+}
+
+// the integer operators, they only take whole dimensionless numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Shl,
+    Shr,
+}
+
+impl BitOp {
+    fn apply(self, a: i128, b: i128) -> Option<i128> {
+        use BitOp::*;
+
+        match self {
+            And => Some(a & b),
+            Or => Some(a | b),
+            // a negative shift goes the other way
+            Shl if b < 0 => Shr.apply(a, b.checked_neg()?),
+            Shr if b < 0 => Shl.apply(a, b.checked_neg()?),
+            Shl => a.checked_mul(2i128.checked_pow(u32::try_from(b).ok()?)?),
+            Shr => Some(a >> b.min(127)),
+        }
+    }
+
+    pub fn checked(self, lhs: Decimal, rhs: Decimal) -> Option<Decimal> {
+        if !lhs.is_integer() || !rhs.is_integer() {
+            return None;
+        }
+
+        Decimal::from_i128(self.apply(lhs.to_i128()?, rhs.to_i128()?)?)
+    }
+}
+
+impl fmt::Display for BitOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sym = match self {
+            BitOp::And => "&",
+            BitOp::Or => "|",
+            BitOp::Shl => "<<",
+            BitOp::Shr => ">>",
+        };
+        write!(f, "{}", sym)
+    }
+}
+
+// the default quantity is a dimensionless zero
+#[derive(Debug, Clone, Default)]
 pub struct Quantity<'a> {
     pub value: Decimal,
     pub unit: Unit<'a>,
@@ -487,7 +1576,7 @@ macro_rules! impl_quantity_cmp {
                 return Err(MorphError::custom(
                     span,
                     format!(
-                        "non-conformable units for '{}': ({} + {})",
+                        "non-conformable units for '{0}': ({1} {0} {2})",
                         stringify!($sym),
                         self.unit, rhs.unit
                     ),
@@ -496,9 +1585,9 @@ macro_rules! impl_quantity_cmp {
             }
 
             if self.value $sym rhs.value {
-                Ok(Quantity::num(dec!(1), span))
+                Ok(Quantity::one(span))
             } else {
-                Ok(Quantity::num(dec!(0), span))
+                Ok(Quantity::zero(span))
             }
         }
     }
@@ -530,128 +1619,2609 @@ impl<'a> Quantity<'a> {
         }
     }
 
-    pub fn equal(&self, rhs: &Quantity<'a>) -> RuntimeResult<'a> {
-        let span = merge_span(&self.span, &rhs.span);
+    // take the span of the node they stand in for, like num
+    pub fn zero(span: Range<usize>) -> Self {
+        Quantity::num(0, span)
+    }
 
-        if self == rhs {
-            Ok(Quantity::num(dec!(1), span))
-        } else {
-            Ok(Quantity::num(dec!(0), span))
-        }
+    pub fn one(span: Range<usize>) -> Self {
+        Quantity::num(1, span)
     }
 
-    pub fn not(self) -> RuntimeResult<'a> {
-        if self.value.is_zero() {
-            Ok(Quantity::num(dec!(1), self.span))
-        } else {
-            Ok(Quantity::num(dec!(0), self.span))
-        }
+    pub fn is_zero(&self) -> bool {
+        self.value.is_zero()
     }
 
-    impl_quantity_cmp!(>= -> greater_eq);
-    impl_quantity_cmp!(> -> greater);
-    impl_quantity_cmp!(<= -> lesser_eq);
-    impl_quantity_cmp!(< -> lesser);
-}
+    // accepts literals like `42`, `5 m`, `3.2 kg/s^2` or `5 [m/s]`, see Unit::parse
+    pub fn parse(src: &'a str) -> RuntimeResult<'a> {
+        let mut parser = UnitParser {
+            src,
+            tokens: Token::lexer(src).spanned().peekable(),
+        };
 
-impl<'a> fmt::Display for Quantity<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.value, self.unit)
+        let value = match parser.tokens.peek() {
+            Some((Ok(Token::Num(num)), _)) => {
+                let num = *num;
+                parser.tokens.next();
+                num
+            }
+            Some((Ok(Token::Unit(_) | Token::LBracket), _)) => dec!(1),
+            Some(_) => {
+                let (tok, span) = parser.tokens.next().unwrap();
+                return Err(parser.unexpected(tok, span));
+            }
+            None => {
+                return Err(MorphError::custom(
+                    0..0,
+                    "empty quantity literal",
+                    ErrorType::UndefinedSyntax,
+                ))
+            }
+        };
+
+        let unit = parser.unit()?;
+        Ok(Quantity::new(value, unit, 0..src.len()))
     }
-}
 
-impl<'a> ops::Add for Quantity<'a> {
-    // type Output = Option<Quantity<'a>>;
-    type Output = RuntimeResult<'a>;
+    pub fn value(&self) -> Decimal {
+        self.value
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        let mut res = self.clone();
+    pub fn unit(&self) -> &Unit<'a> {
+        &self.unit
+    }
 
-        if self.unit == rhs.unit {
-            res.value += rhs.value;
-            Ok(res)
-        } else {
-            let span = merge_span(&self.span, &rhs.span);
-            Err(MorphError::custom(
-                span,
-                format!(
-                    "non-conformable units for '+': ({} + {})",
-                    self.unit, rhs.unit
-                ),
-                ErrorType::TypeError,
-            ))
+    pub fn into_parts(self) -> (Decimal, Unit<'a>) {
+        (self.value, self.unit)
+    }
+
+    // the check '+' and '-' share: the same dimension on both sides and no
+    // temperature scale, where adding is ambiguous
+    fn conform(&self, rhs: &Quantity<'a>) -> Result<(), QuantityError> {
+        let affine = self
+            .unit
+            .0
+            .iter()
+            .chain(&rhs.unit.0)
+            .find(|u| AFFINE_UNITS.contains(&u.name));
+
+        if let Some(u) = affine {
+            return Err(QuantityError::Affine(u.name.to_string()));
+        }
+
+        match self.unit.same_dimension(&rhs.unit) {
+            true => Ok(()),
+            false => Err(QuantityError::UnitMismatch {
+                lhs: self.unit.to_string(),
+                rhs: rhs.unit.to_string(),
+            }),
         }
     }
-}
 
-impl<'a> ops::Sub for Quantity<'a> {
-    type Output = RuntimeResult<'a>;
+    pub fn try_add(&self, rhs: &Quantity<'a>) -> Result<Quantity<'a>, QuantityError> {
+        self.conform(rhs)?;
+        let value = self
+            .value
+            .checked_add(rhs.value)
+            .ok_or(QuantityError::Overflow)?;
+        Ok(self.combined(rhs, value, self.unit.clone()))
+    }
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        let mut res = rhs.clone();
-        res.value *= dec!(-1);
-        self + res
+    pub fn try_sub(&self, rhs: &Quantity<'a>) -> Result<Quantity<'a>, QuantityError> {
+        self.conform(rhs)?;
+        let value = self
+            .value
+            .checked_sub(rhs.value)
+            .ok_or(QuantityError::Overflow)?;
+        Ok(self.combined(rhs, value, self.unit.clone()))
     }
-}
 
-impl<'a> ops::Mul for Quantity<'a> {
-    type Output = RuntimeResult<'a>;
+    pub fn try_mul(&self, rhs: &Quantity<'a>) -> Result<Quantity<'a>, QuantityError> {
+        let unit = self.unit.clone() * rhs.unit.clone();
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        let span = merge_span(&self.span, &rhs.span);
+        // a zero factor skips the multiplication, the unit still combines
+        if self.is_zero() || rhs.is_zero() {
+            return Ok(self.combined(rhs, dec!(0), unit));
+        }
+
+        let value = self
+            .value
+            .checked_mul(rhs.value)
+            .ok_or(QuantityError::Overflow)?;
+        Ok(self.combined(rhs, value, unit))
+    }
+
+    pub fn try_div(&self, rhs: &Quantity<'a>) -> Result<Quantity<'a>, QuantityError> {
+        if rhs.is_zero() {
+            return Err(QuantityError::DivByZero);
+        }
 
-        if self.value.is_zero() || rhs.value.is_zero() {
-            return Ok(Quantity::num(dec!(0), span));
+        // 1 / x only has to invert the divisor
+        if self.is_scalar() && self.value == dec!(1) {
+            let recip = rhs.clone().recip().map_err(|_| QuantityError::DivByZero)?;
+            return Ok(self.combined(rhs, recip.value, recip.unit));
         }
 
-        let res = self.unit * rhs.unit;
-        Ok(Quantity::new(self.value * rhs.value, res, span))
+        let value = self
+            .value
+            .checked_div(rhs.value)
+            .ok_or(QuantityError::Overflow)?;
+        let unit = self.unit.clone() / rhs.unit.clone();
+        Ok(self.combined(rhs, value, unit))
     }
-}
 
-impl<'a> ops::Div for Quantity<'a> {
-    type Output = RuntimeResult<'a>;
+    // a result spans both operands
+    fn combined(&self, rhs: &Quantity<'a>, value: Decimal, unit: Unit<'a>) -> Quantity<'a> {
+        Quantity::new(value, unit, merge_span(&self.span, &rhs.span))
+    }
 
-    fn div(self, rhs: Self) -> Self::Output {
-        let span = merge_span(&self.span, &rhs.span);
+    // rescales to the registered prefix of the same base that brings the
+    // magnitude into [1, 1000), compound units and scalars are left alone
+    pub fn humanize(&self, table: &ConversionTable<'a>) -> Quantity<'a> {
+        let name = match self.unit.0.as_slice() {
+            [u] if u.exp == dec!(1) => u.name,
+            _ => return self.clone(),
+        };
 
-        if rhs.value.is_zero() {
-            Err(MorphError::custom(
-                span,
-                "division by zero",
-                ErrorType::ZeroDivision,
+        let (base, factor) = table.resolve(name);
+        let value = match self.value.checked_mul(factor) {
+            Some(value) => value,
+            None => return self.clone(),
+        };
+
+        let best = table
+            .factors
+            .keys()
+            .map(|name| (*name, table.resolve(name)))
+            .filter(|(_, (b, _))| *b == base)
+            .map(|(name, (_, f))| (name, f))
+            .chain([(base, dec!(1))])
+            .filter_map(|(name, f)| value.checked_div(f).map(|v| (name, f, v)))
+            .filter(|(_, _, v)| v.abs() >= dec!(1) && v.abs() < dec!(1000))
+            .max_by(|(n1, f1, _), (n2, f2, _)| f1.cmp(f2).then(n2.cmp(n1)));
+
+        match best {
+            Some((name, _, value)) => Quantity::new(
+                value.normalize(),
+                UnitAtom::base(name).into(),
+                self.span.clone(),
+            ),
+            None => self.clone(),
+        }
+    }
+
+    pub fn convert_temperature(
+        &self,
+        target: &Unit<'a>,
+        table: &ConversionTable<'a>,
+    ) -> RuntimeResult<'a> {
+        let single = |unit: &Unit<'a>| match unit.0.as_slice() {
+            [u] if u.exp == dec!(1) => table.affine.get(u.name),
+            _ => None,
+        };
+
+        let value = match (single(&self.unit), single(target)) {
+            (Some(from), Some(to)) => from.to_kelvin(self.value).and_then(|k| to.kelvin_to(k)),
+            _ => None,
+        };
+
+        value
+            .map(|value| Quantity::new(value, target.clone(), self.span.clone()))
+            .ok_or(MorphError::custom(
+                self.span.clone(),
+                format!("can't convert temperature {} to {}", self.unit, target),
+                ErrorType::TypeError,
             ))
+    }
+
+    // like Display, with `sep` between every three digits of the integer part
+    pub fn format_grouped(&self, sep: char) -> String {
+        let num = format_number(&self.value);
+        let (sign, num) = match num.strip_prefix('-') {
+            Some(num) => ("-", num),
+            None => ("", num.as_str()),
+        };
+        let (int, frac) = match num.find('.') {
+            Some(i) => num.split_at(i),
+            None => (num, ""),
+        };
+
+        let mut grouped = String::new();
+        for (i, digit) in int.chars().enumerate() {
+            if i != 0 && (int.len() - i) % 3 == 0 {
+                grouped.push(sep);
+            }
+            grouped.push(digit);
+        }
+
+        let value = format!("{}{}{}", sign, grouped, frac);
+        if self.is_scalar() {
+            value
         } else {
-            let res = self.unit / rhs.unit;
-            Ok(Quantity::new(self.value / rhs.value, res, span))
+            format!("{} {}", value, self.unit)
         }
     }
-}
 
-impl<'a> ops::BitXor for Quantity<'a> {
-    type Output = RuntimeResult<'a>;
+    // like Display, with the value as a mantissa in [1, 1000) and an exponent
+    // that is a multiple of three, 47000 m is shown as 47e3 [m]
+    pub fn to_engineering(&self) -> String {
+        let value = self.value.normalize();
 
-    fn bitxor(self, rhs: Self) -> Self::Output {
-        let span = merge_span(&self.span, &rhs.span);
+        let value = if value.is_zero() {
+            "0".to_string()
+        } else {
+            let digits = value.mantissa().unsigned_abs().to_string().len() as i64;
+            let exp = (digits - 1 - value.scale() as i64).div_euclid(3) * 3;
+
+            // moving the decimal point keeps this exact, 10^-30 isn't representable
+            let scale = value.scale() as i64 + exp;
+            let mantissa = match u32::try_from(scale) {
+                Ok(scale) => Decimal::from_i128_with_scale(value.mantissa(), scale),
+                Err(_) => {
+                    let shift = 10i128.pow(scale.unsigned_abs() as u32);
+                    Decimal::from_i128_with_scale(value.mantissa() * shift, 0)
+                }
+            };
+
+            match exp {
+                0 => format_number(&mantissa),
+                _ => format!("{}e{}", format_number(&mantissa), exp),
+            }
+        };
 
-        if !rhs.unit.has_units() {
-            let mut res = self.clone();
-            // res.value = Decimal::powd(&res.value, rhs.value);
-            panic!("pow not implemented");
-            res.unit = res.unit.pow(rhs.value);
-            Ok(res)
+        if self.is_scalar() {
+            value
         } else {
-            Err(MorphError::custom(
+            format!("{} {}", value, self.unit)
+        }
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        self.unit.is_dimensionless()
+    }
+
+    pub fn not(self) -> RuntimeResult<'a> {
+        if self.is_zero() {
+            Ok(Quantity::one(self.span))
+        } else {
+            Ok(Quantity::zero(self.span))
+        }
+    }
+
+    pub fn pow(self, rhs: Quantity<'a>) -> RuntimeResult<'a> {
+        let span = merge_span(&self.span, &rhs.span);
+
+        if rhs.unit.has_units() {
+            return Err(MorphError::custom(
                 span,
                 format!("exponent can't contain a unit, found: {rhs}"),
                 ErrorType::TypeError,
-            ))
+            ));
         }
-    }
-}
+
+        match checked_pow(self.value, rhs.value) {
+            Some(value) => Ok(Quantity::new(value, self.unit.pow(rhs.value), span)),
+            None if rhs.value.is_integer() && !self.value.is_zero() => Err(overflow(span, "^")),
+            None => Err(MorphError::custom(
+                span,
+                format!("could not compute ({}) ^ ({})", self, rhs),
+                ErrorType::DomainError,
+            )),
+        }
+    }
+
+    pub fn recip(self) -> RuntimeResult<'a> {
+        match dec!(1).checked_div(self.value) {
+            Some(value) => Ok(Quantity::new(value, self.unit.reciprocal(), self.span)),
+            None => Err(MorphError::custom(
+                self.span,
+                "reciprocal of zero",
+                ErrorType::ZeroDivision,
+            )),
+        }
+    }
+
+    pub fn simplify(self) -> Quantity<'a> {
+        Quantity {
+            unit: self.unit.normalized(),
+            ..self
+        }
+    }
+
+    pub fn bitwise(self, rhs: Quantity<'a>, op: BitOp) -> RuntimeResult<'a> {
+        let span = merge_span(&self.span, &rhs.span);
+
+        for q in [&self, &rhs] {
+            if q.unit.has_units() {
+                return Err(MorphError::custom(
+                    q.span.clone(),
+                    format!("'{}' needs dimensionless operands, found: {}", op, q),
+                    ErrorType::TypeError,
+                ));
+            }
+            if !q.value.is_integer() {
+                return Err(MorphError::custom(
+                    q.span.clone(),
+                    format!("'{}' needs whole numbers, found: {}", op, q),
+                    ErrorType::DomainError,
+                ));
+            }
+        }
+
+        match op.checked(self.value, rhs.value) {
+            Some(value) => Ok(Quantity::num(value, span)),
+            None => Err(overflow(span, &op.to_string())),
+        }
+    }
+
+    // for live results that shouldn't fail while typing: an overflow clamps to
+    // Decimal::MAX or MIN, so the value is wrong but the unit still holds. A
+    // unit mismatch is an error as with +
+    pub fn saturating_add(self, rhs: Quantity<'a>) -> RuntimeResult<'a> {
+        match self.clone() + rhs.clone() {
+            Err(err) if err.typ == ErrorType::Overflow => Ok(Quantity::new(
+                self.value.saturating_add(rhs.value),
+                self.unit,
+                merge_span(&self.span, &rhs.span),
+            )),
+            res => res,
+        }
+    }
+
+    // like saturating_add, the only way * fails is an overflow
+    pub fn saturating_mul(self, rhs: Quantity<'a>) -> Quantity<'a> {
+        match self.clone() * rhs.clone() {
+            Ok(res) => res,
+            Err(_) => Quantity::new(
+                self.value.saturating_mul(rhs.value),
+                self.unit * rhs.unit,
+                merge_span(&self.span, &rhs.span),
+            ),
+        }
+    }
+
+    // for results that went through f64, where exact equality is too strict.
+    // Both sides are compared in base units like with equals_dimensional, a
+    // difference too large for a Decimal is never within epsilon
+    pub fn approx_eq(
+        &self,
+        other: &Quantity<'a>,
+        epsilon: Decimal,
+        table: &ConversionTable<'a>,
+    ) -> bool {
+        let (lhs, rhs) = (self.to_base_units(table), other.to_base_units(table));
+        lhs.unit.same_dimension(&rhs.unit)
+            && lhs
+                .value
+                .checked_sub(rhs.value)
+                .is_some_and(|diff| diff.abs() <= epsilon)
+    }
+
+    // applies `f` to the value and keeps the unit and span
+    #[inline]
+    pub fn map_value<F: FnOnce(Decimal) -> Decimal>(self, f: F) -> Quantity<'a> {
+        Quantity {
+            value: f(self.value),
+            ..self
+        }
+    }
+
+    pub fn abs(self) -> Quantity<'a> {
+        self.map_value(|v| v.abs())
+    }
+
+    pub fn floor(self) -> Quantity<'a> {
+        self.map_value(|v| v.floor())
+    }
+
+    pub fn ceil(self) -> Quantity<'a> {
+        self.map_value(|v| v.ceil())
+    }
+
+    // halves go to the even neighbour, 2.5 rounds to 2 and 3.5 to 4
+    pub fn round(self) -> Quantity<'a> {
+        self.map_value(|v| v.round())
+    }
+
+    pub fn trunc(self) -> Quantity<'a> {
+        self.map_value(|v| v.trunc())
+    }
+
+    // halves every exponent, so sqrt(2 m) is fine and yields m^0.5
+    pub fn sqrt(self) -> RuntimeResult<'a> {
+        match self.value.sqrt() {
+            Some(value) => Ok(Quantity::new(
+                value.normalize(),
+                self.unit.pow(dec!(0.5)),
+                self.span,
+            )),
+            None => Err(MorphError::custom(
+                self.span.clone(),
+                format!("could not compute sqrt({})", self),
+                ErrorType::DomainError,
+            )),
+        }
+    }
+
+    // rewrites the unit in the base units of `table`, prefixed names resolve
+    // even if they weren't registered. Mass is given in kg like in SI, not in
+    // the unprefixed g. A value that can't be represented is returned unchanged
+    pub fn to_base_units(&self, table: &ConversionTable<'a>) -> Quantity<'a> {
+        let mut table = table.clone();
+        table.register_prefixes(&self.unit);
+        // the expansion of a derived unit may be prefixed itself, like kg in N
+        let (expanded, _) = table.to_base(&self.unit);
+        table.register_prefixes(&expanded);
+
+        let (unit, factor) = table.to_base(&self.unit);
+        let (unit, mass) = kilograms(unit);
+
+        match factor
+            .zip(mass)
+            .and_then(|(f1, f2)| f1.checked_mul(f2))
+            .and_then(|f| self.value.checked_mul(f))
+        {
+            Some(value) => Quantity::new(value, unit, self.span.clone()),
+            None => self.clone(),
+        }
+    }
+
+    // 1 km and 1000 m are equal here, unlike with ==
+    pub fn equals_dimensional(&self, other: &Quantity<'a>, table: &ConversionTable<'a>) -> bool {
+        let (lhs, rhs) = (self.to_base_units(table), other.to_base_units(table));
+        lhs.unit == rhs.unit && lhs.value == rhs.value
+    }
+
+    pub fn expand_derived(&self, table: &DerivedUnits<'a>) -> Quantity<'a> {
+        Quantity {
+            unit: table.expand(&self.unit),
+            ..self.clone()
+        }
+    }
+
+    pub fn round_dp(&self, dp: u32) -> Quantity<'a> {
+        self.clone().map_value(|v| v.round_dp(dp))
+    }
+
+    // zero stays zero, a result that can't be represented keeps the original value
+    pub fn to_sig_figs(&self, n: u32) -> Quantity<'a> {
+        self.clone().map_value(|v| v.round_sf(n).unwrap_or(v))
+    }
+
+    impl_quantity_cmp!(== -> equal);
+    impl_quantity_cmp!(>= -> greater_eq);
+    impl_quantity_cmp!(> -> greater);
+    impl_quantity_cmp!(<= -> lesser_eq);
+    impl_quantity_cmp!(< -> lesser);
+
+    pub fn convert_to(&self, target: &Unit<'a>, table: &ConversionTable<'a>) -> RuntimeResult<'a> {
+        let (from, from_factor) = table.to_base(&self.unit);
+        let (to, to_factor) = table.to_base(target);
+
+        let value = match (from_factor, to_factor) {
+            (Some(f1), Some(f2)) if from == to && !f2.is_zero() => {
+                self.value.checked_mul(f1).and_then(|v| v.checked_div(f2))
+            }
+            _ => None,
+        };
+
+        value
+            .map(|value| Quantity::new(value, target.clone(), self.span.clone()))
+            .ok_or(MorphError::custom(
+                self.span.clone(),
+                format!("can't convert {} to {}", self.unit, target),
+                ErrorType::TypeError,
+            ))
+    }
+}
+
+// a scale relative to kelvin: value = kelvin * factor + offset, the factors
+// stay exact in that direction (degF = K * 1.8 - 459.67)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AffineConversion {
+    pub factor: Decimal,
+    pub offset: Decimal,
+}
+
+impl AffineConversion {
+    pub fn kelvin_to(self, kelvin: Decimal) -> Option<Decimal> {
+        kelvin.checked_mul(self.factor)?.checked_add(self.offset)
+    }
+
+    pub fn to_kelvin(self, value: Decimal) -> Option<Decimal> {
+        value.checked_sub(self.offset)?.checked_div(self.factor)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConversionTable<'a> {
+    factors: HashMap<&'a str, (&'a str, Decimal)>,
+    affine: HashMap<&'a str, AffineConversion>,
+    derived: DerivedUnits<'a>,
+}
+
+impl<'a> ConversionTable<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn register(&mut self, name: &'a str, base: &'a str, factor: Decimal) {
+        self.factors.insert(name, (base, factor));
+    }
+
+    // lets SI prefixed names like km convert to their base unit
+    pub fn register_prefixes(&mut self, unit: &Unit<'a>) {
+        for atom in &unit.0 {
+            let ident = UnitIdent::new(atom.name);
+            if ident.name != ident.base && !self.factors.contains_key(ident.name) {
+                self.register(ident.name, ident.base, ident.prefix);
+            }
+        }
+    }
+
+    // derived units are expanded after prefixes and factors are resolved, so
+    // N and mN convert to kg m/s^2
+    pub fn register_derived(&mut self, name: &'a str, expansion: Unit<'a>) {
+        self.derived.register(name, expansion);
+    }
+
+    pub fn register_affine(&mut self, name: &'a str, conversion: AffineConversion) {
+        self.affine.insert(name, conversion);
+    }
+
+    pub fn temperatures() -> Self {
+        let mut table = Self::new();
+        let scale = |factor, offset| AffineConversion { factor, offset };
+
+        table.register_affine("K", scale(dec!(1), dec!(0)));
+        table.register_affine("degC", scale(dec!(1), dec!(-273.15)));
+        table.register_affine("degF", scale(dec!(1.8), dec!(-459.67)));
+        table
+    }
+
+    pub fn resolve(&self, name: &'a str) -> (&'a str, Decimal) {
+        let mut name = name;
+        let mut factor = dec!(1);
+
+        // the bound guards against cyclic registrations
+        for _ in 0..=self.factors.len() {
+            match self.factors.get(name) {
+                Some((base, f)) => {
+                    name = base;
+                    factor *= f;
+                }
+                None => break,
+            }
+        }
+
+        (name, factor)
+    }
+
+    pub fn to_base(&self, unit: &Unit<'a>) -> (Unit<'a>, Option<Decimal>) {
+        let (resolved, factor) = self.resolve_atoms(unit);
+        let expanded = self.derived.expand(&resolved);
+
+        if expanded == resolved {
+            return (resolved, factor);
+        }
+
+        let (base, expanded_factor) = self.resolve_atoms(&expanded);
+        let factor = factor
+            .zip(expanded_factor)
+            .and_then(|(f1, f2)| f1.checked_mul(f2));
+        (base, factor)
+    }
+
+    fn resolve_atoms(&self, unit: &Unit<'a>) -> (Unit<'a>, Option<Decimal>) {
+        let mut base = Unit::none();
+        let mut factor = Some(dec!(1));
+
+        for atom in &unit.0 {
+            let (name, f) = self.resolve(atom.name);
+            factor = factor
+                .zip(checked_pow(f, atom.exp))
+                .and_then(|(acc, f)| acc.checked_mul(f));
+            base.extend([UnitAtom {
+                name,
+                exp: atom.exp,
+                span: atom.span.clone(),
+            }]);
+        }
+
+        (base, factor)
+    }
+}
+
+// prefixes resolve down to g, the SI base unit of mass is kg
+fn kilograms(unit: Unit<'_>) -> (Unit<'_>, Option<Decimal>) {
+    let mut base = Unit::none();
+    let mut factor = Some(dec!(1));
+
+    for atom in unit.0 {
+        let atom = match atom.name {
+            "g" => {
+                factor = factor
+                    .zip(checked_pow(dec!(0.001), atom.exp))
+                    .and_then(|(acc, f)| acc.checked_mul(f));
+                UnitAtom { name: "kg", ..atom }
+            }
+            _ => atom,
+        };
+        base.extend([atom]);
+    }
+
+    (base, factor)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DerivedUnits<'a> {
+    expansions: HashMap<&'a str, Unit<'a>>,
+}
+
+impl<'a> DerivedUnits<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn register(&mut self, name: &'a str, expansion: Unit<'a>) {
+        self.expansions.insert(name, expansion);
+    }
+
+    pub fn expand(&self, unit: &Unit<'a>) -> Unit<'a> {
+        let mut unit = unit.clone();
+
+        // the bound guards against cyclic registrations
+        for _ in 0..=self.expansions.len() {
+            if !unit.0.iter().any(|u| self.expansions.contains_key(u.name)) {
+                break;
+            }
+
+            unit = unit.0.into_iter().fold(Unit::none(), |acc, atom| {
+                match self.expansions.get(atom.name) {
+                    Some(expansion) => acc * expansion.clone().pow(atom.exp),
+                    None => acc * Unit::from(atom),
+                }
+            });
+        }
+
+        unit
+    }
+}
+
+impl<'a> fmt::Display for Quantity<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_scalar() {
+            write!(f, "{}", format_number(&self.value))
+        } else {
+            write!(f, "{} {}", format_number(&self.value), self.unit)
+        }
+    }
+}
+
+// the result of a statement, a list can be stored in a variable, passed to
+// a user function and converted, but the other operations need a quantity
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Quantity(Quantity<'a>),
+    List(Vec<Quantity<'a>>),
+}
+
+impl<'a> Value<'a> {
+    // applies `f` to the quantity or to every element of the list
+    pub fn try_map<E>(
+        self,
+        mut f: impl FnMut(Quantity<'a>) -> Result<Quantity<'a>, E>,
+    ) -> Result<Self, E> {
+        match self {
+            Value::Quantity(q) => f(q).map(Value::Quantity),
+            Value::List(items) => items
+                .into_iter()
+                .map(f)
+                .collect::<Result<_, _>>()
+                .map(Value::List),
+        }
+    }
+
+    pub fn map(self, mut f: impl FnMut(Quantity<'a>) -> Quantity<'a>) -> Self {
+        match self {
+            Value::Quantity(q) => Value::Quantity(f(q)),
+            Value::List(items) => Value::List(items.into_iter().map(f).collect()),
+        }
+    }
+
+    pub fn round_dp(&self, dp: u32) -> Self {
+        let round = |q: &Quantity<'a>| q.round_dp(dp);
+
+        match self {
+            Value::Quantity(q) => Value::Quantity(round(q)),
+            Value::List(items) => Value::List(items.iter().map(round).collect()),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Value<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Quantity(q) => write!(f, "{}", q),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// why '+', '-', '*' or '/' failed, the ops turn it into a MorphError that
+// points into the source
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuantityError {
+    UnitMismatch { lhs: String, rhs: String },
+    DivByZero,
+    Overflow,
+    // the temperature scale on one of the sides
+    Affine(String),
+}
+
+impl QuantityError {
+    pub fn locate<'a>(self, op: &str, lhs: &Quantity<'a>, rhs: &Quantity<'a>) -> MorphError<'a> {
+        let span = merge_span(&lhs.span, &rhs.span);
+
+        match self {
+            QuantityError::UnitMismatch { lhs: l, rhs: r } => {
+                // the atom that differs is the one to fix
+                let span = rhs
+                    .unit
+                    .mismatch_span(&lhs.unit)
+                    .or_else(|| lhs.unit.mismatch_span(&rhs.unit))
+                    .unwrap_or(span);
+                MorphError::custom(
+                    span,
+                    format!("non-conformable units for '{}': ({} {} {})", op, l, op, r),
+                    ErrorType::TypeError,
+                )
+            }
+            // points at the divisor, the operand that has to change
+            QuantityError::DivByZero => MorphError::custom(
+                rhs.span.clone(),
+                "division by zero",
+                ErrorType::ZeroDivision,
+            ),
+            QuantityError::Overflow => overflow(span, op),
+            QuantityError::Affine(name) => MorphError::custom(
+                span,
+                format!(
+                    "'{}' on {} is ambiguous, convert to K first to use temperature deltas",
+                    op, name
+                ),
+                ErrorType::TypeError,
+            ),
+        }
+    }
+}
+
+impl fmt::Display for QuantityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantityError::UnitMismatch { lhs, rhs } => {
+                write!(f, "non-conformable units: {} and {}", lhs, rhs)
+            }
+            QuantityError::DivByZero => write!(f, "division by zero"),
+            QuantityError::Overflow => write!(f, "arithmetic overflow"),
+            QuantityError::Affine(name) => write!(f, "{} is ambiguous here", name),
+        }
+    }
+}
+
+fn overflow<'a>(span: Range<usize>, op: &str) -> MorphError<'a> {
+    MorphError::custom(
+        span,
+        format!("arithmetic overflow in '{}'", op),
+        ErrorType::Overflow,
+    )
+}
+
+impl<'a> ops::Add for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.try_add(&rhs).map_err(|e| e.locate("+", &self, &rhs))
+    }
+}
+
+impl<'a> ops::Neg for Quantity<'a> {
+    type Output = Quantity<'a>;
+
+    fn neg(self) -> Self::Output {
+        self.map_value(|v| -v)
+    }
+}
+
+impl<'a> ops::Sub for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.try_sub(&rhs).map_err(|e| e.locate("-", &self, &rhs))
+    }
+}
+
+impl<'a> ops::Mul for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.try_mul(&rhs).map_err(|e| e.locate("*", &self, &rhs))
+    }
+}
+
+impl<'a> ops::Div for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self.try_div(&rhs).map_err(|e| e.locate("/", &self, &rhs))
+    }
+}
+
+// scaling by a bare number keeps the unit and the span
+impl<'a> ops::Mul<Decimal> for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn mul(mut self, rhs: Decimal) -> Self::Output {
+        self.value = self
+            .value
+            .checked_mul(rhs)
+            .ok_or_else(|| overflow(self.span.clone(), "*"))?;
+        Ok(self)
+    }
+}
+
+impl<'a> ops::Mul<Quantity<'a>> for Decimal {
+    type Output = RuntimeResult<'a>;
+
+    fn mul(self, rhs: Quantity<'a>) -> Self::Output {
+        rhs * self
+    }
+}
+
+impl<'a> ops::Div<Decimal> for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn div(mut self, rhs: Decimal) -> Self::Output {
+        if rhs.is_zero() {
+            return Err(MorphError::custom(
+                self.span,
+                "division by zero",
+                ErrorType::ZeroDivision,
+            ));
+        }
+
+        self.value = self
+            .value
+            .checked_div(rhs)
+            .ok_or_else(|| overflow(self.span.clone(), "/"))?;
+        Ok(self)
+    }
+}
+
+impl<'a> ops::Rem for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        let span = merge_span(&self.span, &rhs.span);
+
+        if !self.unit.same_dimension(&rhs.unit) {
+            Err(MorphError::custom(
+                span,
+                format!(
+                    "non-conformable units for 'mod': ({} mod {})",
+                    self.unit, rhs.unit
+                ),
+                ErrorType::TypeError,
+            ))
+        } else if rhs.is_zero() {
+            Err(MorphError::custom(
+                rhs.span,
+                "modulo by zero",
+                ErrorType::ZeroDivision,
+            ))
+        } else {
+            Ok(Quantity::new(self.value % rhs.value, self.unit, span))
+        }
+    }
+}
+
+impl<'a> ops::BitXor for Quantity<'a> {
+    type Output = RuntimeResult<'a>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        self.pow(rhs)
+    }
+}
 
 impl<'a> cmp::PartialEq for Quantity<'a> {
     fn eq(&self, other: &Self) -> bool {
         self.value == other.value && self.unit == other.unit
     }
 }
+
+impl<'a> cmp::Eq for Quantity<'a> {}
+
+impl<'a> Hash for Quantity<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state);
+        self.unit.hash(state);
+    }
+}
+
+/// Quantities are ordered by value if their units match. `None` means the
+/// two quantities are incomparable, not that they are equal.
+impl<'a> cmp::PartialOrd for Quantity<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        if self.unit == other.unit {
+            self.value.partial_cmp(&other.value)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use std::marker::PhantomData;
+
+    use serde::{
+        de::{self, EnumAccess, MapAccess, VariantAccess},
+        ser::{SerializeStruct, SerializeStructVariant},
+        Deserialize, Deserializer, Serialize, Serializer,
+    };
+
+    use super::*;
+
+    // serde_derive is not available, so structs are (de)serialized field by field
+    macro_rules! impl_struct_serde {
+        ($name: ident { $($field: ident: $ty: ty),* }) => {
+            impl<'a> Serialize for $name<'a> {
+                fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                    let fields = [$(stringify!($field)),*];
+                    let mut st = s.serialize_struct(stringify!($name), fields.len())?;
+                    $(st.serialize_field(stringify!($field), &self.$field)?;)*
+                    st.end()
+                }
+            }
+
+            impl<'de: 'a, 'a> Deserialize<'de> for $name<'a> {
+                fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                    struct StructVisitor<'a>(PhantomData<&'a ()>);
+
+                    impl<'de: 'a, 'a> de::Visitor<'de> for StructVisitor<'a> {
+                        type Value = $name<'a>;
+
+                        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                            write!(f, "struct {}", stringify!($name))
+                        }
+
+                        fn visit_map<M: MapAccess<'de>>(
+                            self,
+                            mut map: M,
+                        ) -> Result<Self::Value, M::Error> {
+                            $(let mut $field: Option<$ty> = None;)*
+
+                            while let Some(key) = map.next_key::<String>()? {
+                                match key.as_str() {
+                                    $(stringify!($field) => $field = Some(map.next_value()?),)*
+                                    _ => {
+                                        map.next_value::<de::IgnoredAny>()?;
+                                    }
+                                }
+                            }
+
+                            Ok($name {
+                                $($field: $field.ok_or_else(|| {
+                                    de::Error::missing_field(stringify!($field))
+                                })?,)*
+                            })
+                        }
+                    }
+
+                    const FIELDS: &[&str] = &[$(stringify!($field)),*];
+                    d.deserialize_struct(stringify!($name), FIELDS, StructVisitor(PhantomData))
+                }
+            }
+        };
+    }
+
+    // payloads of the struct variants of NodeType
+    struct IfElseRepr<'a> {
+        cond: Box<Node<'a>>,
+        if_body: Box<Node<'a>>,
+        else_body: Option<Box<Node<'a>>>,
+    }
+
+    struct DefFnRepr<'a> {
+        name: &'a str,
+        params: Vec<&'a str>,
+        body: Box<Node<'a>>,
+    }
+
+    struct ConvertRepr<'a> {
+        expr: Box<Node<'a>>,
+        target: Unit<'a>,
+    }
+
+    struct FnCallRepr<'a> {
+        name: &'a str,
+        args: Vec<Node<'a>>,
+    }
+
+    impl_struct_serde!(UnitAtom { name: &'a str, exp: Decimal, span: Option<Range<usize>> });
+    impl_struct_serde!(Quantity { value: Decimal, unit: Unit<'a>, span: Range<usize> });
+    impl_struct_serde!(Node { typ: NodeType<'a>, span: Range<usize> });
+    impl_struct_serde!(IfElseRepr { cond: Box<Node<'a>>, if_body: Box<Node<'a>>, else_body: Option<Box<Node<'a>>> });
+    impl_struct_serde!(DefFnRepr { name: &'a str, params: Vec<&'a str>, body: Box<Node<'a>> });
+    impl_struct_serde!(ConvertRepr { expr: Box<Node<'a>>, target: Unit<'a> });
+    impl_struct_serde!(FnCallRepr { name: &'a str, args: Vec<Node<'a>> });
+
+    impl<'a> Serialize for Unit<'a> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_newtype_struct("Unit", &self.0)
+        }
+    }
+
+    impl<'de: 'a, 'a> Deserialize<'de> for Unit<'a> {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            Vec::deserialize(d).map(Unit)
+        }
+    }
+
+    // in the order of NodeType::variant
+    const VARIANTS: &[&str] = &[
+        "Def",
+        "DefFn",
+        "IfElse",
+        "Add",
+        "Sub",
+        "Mul",
+        "Div",
+        "Mod",
+        "Pow",
+        "BitAnd",
+        "BitOr",
+        "Shl",
+        "Shr",
+        "UnryNot",
+        "UnrySub",
+        "Percent",
+        "Abs",
+        "Unit",
+        "Num",
+        "FnCall",
+        "Assign",
+        "AddAssign",
+        "SubAssign",
+        "MulAssign",
+        "DivAssign",
+        "PowAssign",
+        "Equal",
+        "NeEqual",
+        "GreaterEqual",
+        "LesserEqual",
+        "Greater",
+        "Lesser",
+        "Scope",
+        "List",
+        "Convert",
+        "ParseError",
+    ];
+
+    impl<'a> Serialize for NodeType<'a> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            use NodeType::*;
+
+            let (index, tag) = self.variant();
+            macro_rules! newtype {
+                ($val: expr) => {
+                    s.serialize_newtype_variant("NodeType", index, tag, $val)
+                };
+            }
+
+            match self {
+                Def(name) => newtype!(name),
+                DefFn { name, params, body } => {
+                    let mut st = s.serialize_struct_variant("NodeType", index, tag, 3)?;
+                    st.serialize_field("name", name)?;
+                    st.serialize_field("params", params)?;
+                    st.serialize_field("body", body)?;
+                    st.end()
+                }
+                IfElse {
+                    cond,
+                    if_body,
+                    else_body,
+                } => {
+                    let mut st = s.serialize_struct_variant("NodeType", index, tag, 3)?;
+                    st.serialize_field("cond", cond)?;
+                    st.serialize_field("if_body", if_body)?;
+                    st.serialize_field("else_body", else_body)?;
+                    st.end()
+                }
+                Add(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Sub(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Mul(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Div(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Mod(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Pow(lhs, rhs) => newtype!(&(lhs, rhs)),
+                BitAnd(lhs, rhs) => newtype!(&(lhs, rhs)),
+                BitOr(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Shl(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Shr(lhs, rhs) => newtype!(&(lhs, rhs)),
+                UnryNot(val) => newtype!(val),
+                UnrySub(val) => newtype!(val),
+                Percent(val) => newtype!(val),
+                Abs(val) => newtype!(val),
+                Unit(name) => newtype!(name),
+                Num(num) => newtype!(num),
+                FnCall { name, args } => {
+                    let mut st = s.serialize_struct_variant("NodeType", index, tag, 2)?;
+                    st.serialize_field("name", name)?;
+                    st.serialize_field("args", args)?;
+                    st.end()
+                }
+                Assign(name, val) => newtype!(&(name, val)),
+                AddAssign(name, val) => newtype!(&(name, val)),
+                SubAssign(name, val) => newtype!(&(name, val)),
+                MulAssign(name, val) => newtype!(&(name, val)),
+                DivAssign(name, val) => newtype!(&(name, val)),
+                PowAssign(name, val) => newtype!(&(name, val)),
+                Equal(lhs, rhs) => newtype!(&(lhs, rhs)),
+                NeEqual(lhs, rhs) => newtype!(&(lhs, rhs)),
+                GreaterEqual(lhs, rhs) => newtype!(&(lhs, rhs)),
+                LesserEqual(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Greater(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Lesser(lhs, rhs) => newtype!(&(lhs, rhs)),
+                Scope(nodes) => newtype!(nodes),
+                List(items) => newtype!(items),
+                Convert { expr, target } => {
+                    let mut st = s.serialize_struct_variant("NodeType", index, tag, 2)?;
+                    st.serialize_field("expr", expr)?;
+                    st.serialize_field("target", target)?;
+                    st.end()
+                }
+                ParseError => s.serialize_unit_variant("NodeType", index, tag),
+            }
+        }
+    }
+
+    impl<'de: 'a, 'a> Deserialize<'de> for NodeType<'a> {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            struct NodeTypeVisitor<'a>(PhantomData<&'a ()>);
+
+            impl<'de: 'a, 'a> de::Visitor<'de> for NodeTypeVisitor<'a> {
+                type Value = NodeType<'a>;
+
+                fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "enum NodeType")
+                }
+
+                fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+                    use NodeType::*;
+
+                    let (tag, variant): (String, _) = data.variant()?;
+
+                    macro_rules! pair {
+                        ($ctor: expr) => {{
+                            let (lhs, rhs) = variant.newtype_variant()?;
+                            $ctor(lhs, rhs)
+                        }};
+                    }
+
+                    let typ = match tag.as_str() {
+                        "Def" => Def(variant.newtype_variant()?),
+                        "DefFn" => {
+                            let repr: DefFnRepr = variant.newtype_variant()?;
+                            DefFn {
+                                name: repr.name,
+                                params: repr.params,
+                                body: repr.body,
+                            }
+                        }
+                        "IfElse" => {
+                            let repr: IfElseRepr = variant.newtype_variant()?;
+                            NodeType::if_else(repr.cond, repr.if_body, repr.else_body)
+                        }
+                        "Add" => pair!(Add),
+                        "Sub" => pair!(Sub),
+                        "Mul" => pair!(Mul),
+                        "Div" => pair!(Div),
+                        "Mod" => pair!(Mod),
+                        "Pow" => pair!(Pow),
+                        "BitAnd" => pair!(BitAnd),
+                        "BitOr" => pair!(BitOr),
+                        "Shl" => pair!(Shl),
+                        "Shr" => pair!(Shr),
+                        "UnryNot" => UnryNot(variant.newtype_variant()?),
+                        "UnrySub" => UnrySub(variant.newtype_variant()?),
+                        "Percent" => Percent(variant.newtype_variant()?),
+                        "Abs" => Abs(variant.newtype_variant()?),
+                        "Unit" => Unit(variant.newtype_variant()?),
+                        "Num" => Num(variant.newtype_variant()?),
+                        "FnCall" => {
+                            let repr: FnCallRepr = variant.newtype_variant()?;
+                            FnCall {
+                                name: repr.name,
+                                args: repr.args,
+                            }
+                        }
+                        "Assign" => pair!(Assign),
+                        "AddAssign" => pair!(AddAssign),
+                        "SubAssign" => pair!(SubAssign),
+                        "MulAssign" => pair!(MulAssign),
+                        "DivAssign" => pair!(DivAssign),
+                        "PowAssign" => pair!(PowAssign),
+                        "Equal" => pair!(Equal),
+                        "NeEqual" => pair!(NeEqual),
+                        "GreaterEqual" => pair!(GreaterEqual),
+                        "LesserEqual" => pair!(LesserEqual),
+                        "Greater" => pair!(Greater),
+                        "Lesser" => pair!(Lesser),
+                        "Scope" => Scope(variant.newtype_variant()?),
+                        "List" => List(variant.newtype_variant()?),
+                        "Convert" => {
+                            let repr: ConvertRepr = variant.newtype_variant()?;
+                            Convert {
+                                expr: repr.expr,
+                                target: repr.target,
+                            }
+                        }
+                        "ParseError" => {
+                            variant.unit_variant()?;
+                            ParseError
+                        }
+                        _ => return Err(de::Error::unknown_variant(&tag, VARIANTS)),
+                    };
+
+                    Ok(typ)
+                }
+            }
+
+            d.deserialize_enum("NodeType", VARIANTS, NodeTypeVisitor(PhantomData))
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::morph::test_utils::*;
+
+        #[test]
+        fn node_round_trip() {
+            let root = parse("3 m / 2 s; x = f(1, -2)").0.unwrap();
+
+            let json = serde_json::to_string(&root).unwrap();
+            let back: Node = serde_json::from_str(&json).unwrap();
+
+            eq!(back, root);
+            eq!(back.span, root.span);
+
+            let (NodeType::Scope(orig), NodeType::Scope(stmts)) = (&root.typ, &back.typ) else {
+                unreachable!()
+            };
+            eq!(stmts[0].span, orig[0].span);
+            eq!(stmts[1].span, orig[1].span);
+        }
+
+        #[test]
+        fn variant_names() {
+            struct Names;
+            impl<'a> crate::visit::Visitor<'a> for Names {
+                fn visit_node(&mut self, n: &Node<'a>) {
+                    let (index, name) = n.typ.variant();
+                    eq!(VARIANTS[index as usize], name);
+                    crate::visit::walk_node(self, n)
+                }
+            }
+
+            let root = parse("def m; def f(x) { -x }; x = 2 m; x += 1 m : m; f([1] * 3 % 1)")
+                .0
+                .unwrap();
+            crate::visit::Visitor::visit_node(&mut Names, &root);
+        }
+
+        #[test]
+        fn decimal_as_string() {
+            let json = serde_json::to_string(&n(dec!(0.1))).unwrap();
+            eq!(json, r#"{"typ":{"Num":"0.1"},"span":{"start":0,"end":0}}"#);
+        }
+
+        #[test]
+        fn quantity_round_trip() {
+            let q = Quantity::new(dec!(1.5), UnitAtom::base("m").into(), 2..5);
+
+            let json = serde_json::to_string(&q).unwrap();
+            let back: Quantity = serde_json::from_str(&json).unwrap();
+
+            eq!(back, q);
+            eq!(back.span, q.span);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lex(src: &str) -> Vec<Token<'_>> {
+        Token::lexer(src).map(|tok| tok.unwrap()).collect()
+    }
+
+    #[test]
+    fn si_prefix() {
+        let ident = |src| match lex(src).as_slice() {
+            [Token::Unit(ident)] => (ident.base, ident.prefix),
+            toks => panic!("expected a single unit token, found {:?}", toks),
+        };
+
+        assert_eq!(ident("km"), ("m", dec!(1000)));
+        assert_eq!(ident("ms"), ("s", dec!(0.001)));
+        assert_eq!(ident("cm"), ("m", dec!(0.01)));
+        assert_eq!(ident("kg"), ("g", dec!(1000)));
+        assert_eq!(ident("m"), ("m", dec!(1)));
+        assert_eq!(ident("mol"), ("mol", dec!(1)));
+        assert_eq!(ident("min"), ("min", dec!(1)));
+        assert_eq!(ident("meter"), ("meter", dec!(1)));
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            lex("def if else mod"),
+            vec![Token::Def, Token::If, Token::Else, Token::Mod]
+        );
+
+        for word in ["define", "iffy", "elsewhere", "modulo", "de", "i", "if2"] {
+            assert_eq!(lex(word), vec![Token::Unit(UnitIdent::plain(word))]);
+        }
+
+        assert_eq!(
+            lex("iff = 1"),
+            vec![
+                Token::Unit(UnitIdent::plain("iff")),
+                Token::Assign,
+                Token::Num(dec!(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_units() {
+        assert_eq!(lex("`US$`"), vec![Token::Unit(UnitIdent::plain("US$"))]);
+        assert_eq!(
+            lex("5 `\u{b0}C`"),
+            vec![
+                Token::Num(dec!(5)),
+                Token::Unit(UnitIdent::plain("\u{b0}C"))
+            ]
+        );
+        // quoting keeps a name from being read as prefixed
+        assert_eq!(lex("`km`"), vec![Token::Unit(UnitIdent::plain("km"))]);
+
+        let toks: Vec<_> = Token::lexer("2 `kg force").spanned().collect();
+        assert_eq!(toks[1], (Err(LexError::UnterminatedQuote), 2..11));
+        assert_eq!(toks.len(), 2);
+
+        let toks: Vec<_> = Token::lexer("`a\n`b`").spanned().collect();
+        assert_eq!(toks[0], (Err(LexError::UnterminatedQuote), 0..2));
+        assert_eq!(toks[2], (Ok(Token::Unit(UnitIdent::plain("b"))), 3..6));
+
+        // an empty name is one error, not two unterminated quotes
+        let toks: Vec<_> = Token::lexer("2 `` + 1").spanned().collect();
+        assert_eq!(toks[1], (Err(LexError::EmptyQuote), 2..4));
+        assert_eq!(toks.len(), 4);
+    }
+
+    #[test]
+    fn compound_assign() {
+        let x = || Token::Unit(UnitIdent::plain("x"));
+
+        for (src, tok) in [
+            ("+=", Token::AddAssign),
+            ("-=", Token::SubAssign),
+            ("*=", Token::MulAssign),
+            ("/=", Token::DivAssign),
+            ("^=", Token::PowAssign),
+        ] {
+            let expected = vec![x(), tok, Token::Num(dec!(1))];
+            assert_eq!(lex(&format!("x {} 1", src)), expected, "{}", src);
+            assert_eq!(lex(&format!("x{}1", src)), expected, "{}", src);
+        }
+
+        // split apart they stay two tokens
+        assert_eq!(
+            lex("x + = 1"),
+            vec![x(), Token::Add, Token::Assign, Token::Num(dec!(1))]
+        );
+        assert_eq!(
+            lex("x+==1"),
+            vec![x(), Token::AddAssign, Token::Assign, Token::Num(dec!(1))]
+        );
+    }
+
+    #[test]
+    fn crlf() {
+        assert_eq!(
+            lex("1\r\n2"),
+            vec![Token::Num(dec!(1)), Token::NL, Token::Num(dec!(2))]
+        );
+        assert_eq!(
+            lex("1\r2"),
+            vec![Token::Num(dec!(1)), Token::NL, Token::Num(dec!(2))]
+        );
+        assert_eq!(
+            lex("1\r\n\r\n2 // note\r\n"),
+            vec![
+                Token::Num(dec!(1)),
+                Token::NL,
+                Token::NL,
+                Token::Num(dec!(2)),
+                Token::NL
+            ]
+        );
+
+        let spans: Vec<_> = Token::lexer("1\r\n2").spanned().map(|(_, s)| s).collect();
+        assert_eq!(spans, vec![0..1, 1..3, 3..4]);
+    }
+
+    #[test]
+    fn sci_notation() {
+        assert_eq!(lex("1e3"), vec![Token::Num(dec!(1000))]);
+        assert_eq!(lex("4E+2"), vec![Token::Num(dec!(400))]);
+        assert_eq!(lex("1e-3"), vec![Token::Num(dec!(0.001))]);
+        assert_eq!(lex("2.5e-2"), vec![Token::Num(dec!(0.025))]);
+        assert_eq!(lex("1.5e6"), vec![Token::Num(dec!(1500000))]);
+        assert_eq!(
+            lex("2em"),
+            vec![Token::Num(dec!(2)), Token::Unit(UnitIdent::plain("em"))]
+        );
+
+        assert_eq!(
+            Token::lexer("1e99").collect::<Vec<_>>(),
+            vec![Err(LexError::NumOutOfRange)]
+        );
+    }
+
+    #[test]
+    fn decimal_comma() {
+        let comma = LexOptions {
+            decimal_comma: true,
+        };
+        let lex_with = |src| Token::lexer_with_extras(src, comma).collect::<Vec<_>>();
+
+        assert_eq!(lex_with("3,14"), vec![Ok(Token::Num(dec!(3.14)))]);
+        assert_eq!(lex_with("1_000,5"), vec![Ok(Token::Num(dec!(1000.5)))]);
+        assert_eq!(
+            lex_with("2,5 m"),
+            vec![
+                Ok(Token::Num(dec!(2.5))),
+                Ok(Token::Unit(UnitIdent::plain("m")))
+            ]
+        );
+        assert_eq!(lex_with("3.14"), vec![Err(LexError::InvalidSeparator)]);
+        assert_eq!(
+            lex_with("f(1, 2)")[2..4],
+            [Ok(Token::Num(dec!(1))), Err(LexError::CommaDelimiter)]
+        );
+
+        assert_eq!(
+            lex("3,14"),
+            vec![Token::Num(dec!(3)), Token::Comma, Token::Num(dec!(14))]
+        );
+    }
+
+    #[test]
+    fn radix_literal() {
+        assert_eq!(lex("0xFF"), vec![Token::Num(dec!(255))]);
+        assert_eq!(lex("0x1a"), vec![Token::Num(dec!(26))]);
+        assert_eq!(lex("0b1010"), vec![Token::Num(dec!(10))]);
+        assert_eq!(lex("0b0"), vec![Token::Num(dec!(0))]);
+        assert_eq!(
+            lex("0x10 m"),
+            vec![Token::Num(dec!(16)), Token::Unit(UnitIdent::plain("m"))]
+        );
+
+        assert_eq!(
+            Token::lexer("0x").collect::<Vec<_>>(),
+            vec![Err(LexError::MissingDigits)]
+        );
+        assert_eq!(
+            Token::lexer("0b").collect::<Vec<_>>(),
+            vec![Err(LexError::MissingDigits)]
+        );
+        assert_eq!(
+            Token::lexer("0x + 1").collect::<Vec<_>>()[0],
+            Err(LexError::MissingDigits)
+        );
+
+        // only a digit makes the prefix a radix, otherwise it starts a unit
+        let unit = |name| Token::Unit(UnitIdent::new(name));
+        assert_eq!(lex("0bar"), vec![Token::Num(dec!(0)), unit("bar")]);
+        assert_eq!(lex("0xyz"), vec![Token::Num(dec!(0)), unit("xyz")]);
+        assert_eq!(lex("0b2"), vec![Token::Num(dec!(0)), unit("b2")]);
+
+        // fits an i128 but not a Decimal
+        assert_eq!(
+            Token::lexer("0xFFFFFFFFFFFFFFFFFFFFFFFFFF").collect::<Vec<_>>(),
+            vec![Err(LexError::NumOutOfRange)]
+        );
+        // exceeds i128::MAX
+        assert_eq!(
+            Token::lexer("0x1000000000000000000000000000000000").collect::<Vec<_>>(),
+            vec![Err(LexError::NumOutOfRange)]
+        );
+    }
+
+    #[test]
+    fn digit_separator() {
+        assert_eq!(lex("1_000_000"), vec![Token::Num(dec!(1000000))]);
+        assert_eq!(lex("1_000.500_5"), vec![Token::Num(dec!(1000.5005))]);
+        assert_eq!(lex("12.5"), vec![Token::Num(dec!(12.5))]);
+
+        for src in ["_5", "5_", "5__0", "1_.5", "1._5"] {
+            assert_eq!(
+                Token::lexer(src).collect::<Vec<_>>(),
+                vec![Err(LexError::InvalidSeparator)],
+                "{src}"
+            );
+        }
+
+        let unit = |name| Token::Unit(UnitIdent::plain(name));
+        assert_eq!(lex("_"), vec![unit("_")]);
+        assert_eq!(lex("_x"), vec![unit("_x")]);
+        assert_eq!(lex("_ 5"), vec![unit("_"), Token::Num(dec!(5))]);
+    }
+
+    #[test]
+    fn comment() {
+        use Token::*;
+
+        assert_eq!(
+            lex("5 + 3 # add them\n"),
+            vec![Num(dec!(5)), Add, Num(dec!(3)), NL]
+        );
+        assert_eq!(
+            lex("# header\n1; 2"),
+            vec![NL, Num(dec!(1)), NL, Num(dec!(2))]
+        );
+        assert_eq!(lex("1 # no newline"), vec![Num(dec!(1))]);
+        assert_eq!(lex("#"), vec![]);
+    }
+
+    #[test]
+    fn source_pos() {
+        let pos = |line, col| SourcePos { line, col };
+        let src = "a = 1; b\n  c\n";
+
+        assert_eq!(byte_to_pos(src, 0), pos(1, 1));
+        assert_eq!(byte_to_pos(src, 7), pos(1, 8));
+        assert_eq!(byte_to_pos(src, 8), pos(1, 9));
+        assert_eq!(byte_to_pos(src, 11), pos(2, 3));
+        assert_eq!(byte_to_pos(src, src.len()), pos(3, 1));
+        assert_eq!(byte_to_pos(src, 100), pos(3, 1));
+
+        let root = crate::morph::parse(src).0.unwrap();
+        let starts: Vec<_> = match root.typ {
+            NodeType::Scope(nodes) => nodes.iter().map(|n| n.span_start_pos(src)).collect(),
+            _ => unreachable!(),
+        };
+        assert_eq!(starts, [pos(1, 1), pos(1, 8), pos(2, 3)]);
+        assert_eq!(pos(2, 3).to_string(), "line 2, col 3");
+    }
+
+    #[test]
+    fn node_children() {
+        let stmt = |src| crate::morph::parse_program(src).0.remove(0);
+
+        assert_eq!(stmt("2 * (3 + 4)").size(), 5);
+        assert_eq!(stmt("2 * (3 + 4)").depth(), 3);
+        assert_eq!(stmt("x = f(1, 2, -y)").size(), 6);
+        assert_eq!(stmt("if a { b } else { c; d }").size(), 7);
+        assert_eq!(stmt("def m").size(), 1);
+
+        let children = stmt("a - b")
+            .children()
+            .into_iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(children, ["a", "b"]);
+    }
+
+    #[test]
+    fn node_metrics() {
+        let stmt = |src| crate::morph::parse_program(src).0.remove(0);
+
+        let num = Node::new(NodeType::Num(dec!(1)), 0..1);
+        assert_eq!((num.depth(), num.size()), (1, 1));
+        assert_eq!(
+            (stmt("2 * (3 + 4)").depth(), stmt("2 * (3 + 4)").size()),
+            (3, 5)
+        );
+        assert_eq!(stmt("-(-(-x))").depth(), 4);
+        assert_eq!(stmt("f(1, 2 + 3, 4)").depth(), 3);
+
+        // a scope is one level above its deepest statement
+        let scope = Node::new(NodeType::Scope(vec![stmt("a"), stmt("1 + b")]), 0..0);
+        assert_eq!((scope.depth(), scope.size()), (3, 5));
+        let empty = Node::new(NodeType::Scope(vec![]), 0..0);
+        assert_eq!((empty.depth(), empty.size()), (1, 1));
+    }
+
+    #[test]
+    fn unit_display() {
+        let atom = |name, exp| UnitAtom {
+            name,
+            exp,
+            span: None,
+        };
+        let unit = |atoms: &[UnitAtom<'static>]| Unit(atoms.to_vec());
+
+        assert_eq!(unit(&[atom("m", dec!(1))]).to_string(), "[m]");
+        assert_eq!(
+            unit(&[atom("m", dec!(1)), atom("s", dec!(-1))]).to_string(),
+            "[m/s]"
+        );
+        assert_eq!(
+            unit(&[atom("kg", dec!(1)), atom("m", dec!(1)), atom("s", dec!(-2))]).to_string(),
+            "[kg m/s^2]"
+        );
+        assert_eq!(
+            unit(&[
+                atom("kg", dec!(1)),
+                atom("m", dec!(-1)),
+                atom("s", dec!(-2))
+            ])
+            .to_string(),
+            "[kg/(m s^2)]"
+        );
+        assert_eq!(unit(&[atom("s", dec!(-1))]).to_string(), "[1/s]");
+        assert_eq!(unit(&[atom("m", dec!(-0.5))]).to_string(), "[1/m^0.5]");
+        assert_eq!(Unit::none().to_string(), "");
+
+        let m = Unit::from(UnitAtom::base("m"));
+        let s = Unit::from(UnitAtom::base("s"));
+        assert_eq!((m / s.pow(dec!(2))).to_string(), "[m/s^2]");
+    }
+
+    #[test]
+    fn unit_mul_order() {
+        let atom = |name, exp: i32| {
+            Unit::from(UnitAtom {
+                name,
+                exp: exp.into(),
+                span: None,
+            })
+        };
+
+        let unit = atom("a", 1) * atom("b", -1) * atom("c", 1) * atom("d", -1);
+        assert_eq!(
+            unit.0,
+            vec![
+                UnitAtom::base("a"),
+                UnitAtom::base("c"),
+                UnitAtom {
+                    name: "b",
+                    exp: dec!(-1),
+                    span: None,
+                },
+                UnitAtom {
+                    name: "d",
+                    exp: dec!(-1),
+                    span: None,
+                },
+            ]
+        );
+        assert_eq!(unit.to_string(), "[a c/(b d)]");
+
+        let unit = atom("d", -1) * atom("c", 1) * atom("b", -1) * atom("a", 1);
+        assert_eq!(unit.to_string(), "[a c/(b d)]");
+    }
+
+    #[test]
+    fn unit_from_iter() {
+        let m = || UnitAtom::base("m");
+        let s = || UnitAtom::base("s");
+
+        let unit: Unit = [m(), s(), m()].into_iter().collect();
+        assert_eq!(unit, Unit::from(m()).pow(dec!(2)) * s().into());
+        assert_eq!(unit.to_string(), "[m^2 s]");
+
+        let mut unit: Unit = [s()].into_iter().collect();
+        let per_s = UnitAtom {
+            name: "s",
+            exp: dec!(-1),
+            span: None,
+        };
+        unit.extend([m(), per_s]);
+        assert_eq!(unit, Unit::from(m()));
+        assert!(std::iter::empty().collect::<Unit>().is_empty());
+    }
+
+    #[test]
+    fn integer_pow() {
+        let pow = |b: Decimal, e: Decimal| checked_pow(b, e);
+
+        assert_eq!(pow(dec!(2), dec!(20)), Some(dec!(1048576)));
+        assert_eq!(pow(dec!(-3), dec!(3)), Some(dec!(-27)));
+        assert_eq!(pow(dec!(2), dec!(-2)), Some(dec!(0.25)));
+        assert_eq!(pow(dec!(7), dec!(0)), Some(dec!(1)));
+        assert_eq!(pow(dec!(1), dec!(1000000000)), Some(dec!(1)));
+        assert_eq!(pow(dec!(2), dec!(200)), None);
+        assert_eq!(pow(dec!(0), dec!(-1)), None);
+
+        let root = pow(dec!(4), dec!(0.5)).unwrap();
+        assert!((root - dec!(2)).abs() < dec!(0.000000001));
+        assert_eq!(pow(dec!(-4), dec!(0.5)), None);
+
+        let q = |v: Decimal| Quantity::num(v, 0..0);
+        let err = q(dec!(2)).pow(q(dec!(200))).unwrap_err();
+        assert_eq!(err.typ, ErrorType::Overflow);
+        assert_eq!(err.to_string(), "arithmetic overflow in '^'");
+        assert_eq!(
+            q(dec!(0)).pow(q(dec!(-1))).unwrap_err().typ,
+            ErrorType::DomainError
+        );
+    }
+
+    #[test]
+    fn engineering() {
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+        let eng = |v: Decimal| Quantity::num(v, 0..0).to_engineering();
+
+        assert_eq!(q(dec!(47000), "m").to_engineering(), "47e3 [m]");
+        assert_eq!(q(dec!(0.0022), "s").to_engineering(), "2.2e-3 [s]");
+        assert_eq!(q(dec!(-1500000), "m").to_engineering(), "-1.5e6 [m]");
+        assert_eq!(q(dec!(0), "m").to_engineering(), "0 [m]");
+
+        assert_eq!(eng(dec!(1)), "1");
+        assert_eq!(eng(dec!(999.5)), "999.5");
+        assert_eq!(eng(dec!(1000)), "1e3");
+        assert_eq!(eng(dec!(0.001)), "1e-3");
+        assert_eq!(eng(dec!(0.01)), "10e-3");
+        assert_eq!(eng(dec!(0.1)), "100e-3");
+        assert_eq!(eng(dec!(-0.000047)), "-47e-6");
+        assert_eq!(eng(dec!(123456.789)), "123.456789e3");
+        assert_eq!(eng(dec!(0.000)), "0");
+        assert_eq!(eng(Decimal::new(1, 28)), "100e-30");
+        assert_eq!(eng(Decimal::MAX), "79.228162514264337593543950335e27");
+    }
+
+    #[test]
+    fn scale_by_decimal() {
+        let m = |v: Decimal| Quantity::new(v, UnitAtom::base("m").into(), 2..5);
+
+        assert_eq!((m(dec!(5)) * dec!(2)).unwrap(), m(dec!(10)));
+        assert_eq!((dec!(2) * m(dec!(5))).unwrap(), m(dec!(10)));
+        assert_eq!((m(dec!(5)) / dec!(2)).unwrap(), m(dec!(2.5)));
+        assert_eq!((m(dec!(5)) * dec!(2)).unwrap().span, 2..5);
+
+        let err = (m(dec!(5)) / dec!(0)).unwrap_err();
+        assert_eq!(err.typ, ErrorType::ZeroDivision);
+        assert_eq!(err.span.into_range(), 2..5);
+        assert_eq!(
+            (m(Decimal::MAX) * dec!(2)).unwrap_err().typ,
+            ErrorType::Overflow
+        );
+    }
+
+    #[test]
+    fn quantity_simplify() {
+        let atom = |name, exp| UnitAtom {
+            name,
+            exp,
+            span: None,
+        };
+        let unit = Unit(vec![
+            atom("m", dec!(1)),
+            atom("s", dec!(1)),
+            atom("s", dec!(-1)),
+        ]);
+
+        let q = Quantity::new(dec!(2.50), unit, 3..7).simplify();
+        assert_eq!(q.unit.0, vec![atom("m", dec!(1))]);
+        assert_eq!(q.value.to_string(), "2.50");
+        assert_eq!(q.span, 3..7);
+    }
+
+    #[test]
+    fn same_dimension() {
+        let m = || UnitAtom::base("m");
+        let s = || UnitAtom::base("s");
+        let zero = UnitAtom {
+            name: "kg",
+            exp: dec!(0),
+            span: None,
+        };
+
+        assert_eq!(m().with_span(0..1), m().with_span(4..5));
+        assert_eq!(m().with_span(0..1).span(), Some(0..1));
+
+        let ms = Unit(vec![m(), s()]);
+        let sm = Unit(vec![s(), m()]);
+        assert_ne!(ms, sm);
+        assert!(ms.same_dimension(&sm));
+        assert!(ms.same_dimension(&Unit(vec![s(), zero, m()])));
+        assert!(Unit(vec![m(), m()]).same_dimension(&Unit(vec![UnitAtom {
+            name: "m",
+            exp: dec!(2),
+            span: None,
+        }])));
+        assert!(!ms.same_dimension(&Unit(vec![m()])));
+
+        let res = Quantity::new(dec!(1), ms.clone(), 0..0) + Quantity::new(dec!(2), sm, 0..0);
+        assert_eq!(res.unwrap().value, dec!(3));
+    }
+
+    #[test]
+    fn unit_normalized() {
+        let atom = |name, exp| UnitAtom {
+            name,
+            exp,
+            span: None,
+        };
+
+        let unit = Unit(vec![
+            atom("s", dec!(-1)),
+            atom("m", dec!(1)),
+            atom("kg", dec!(0)),
+            atom("m", dec!(2)),
+            atom("A", dec!(-2)),
+            atom("s", dec!(-1)),
+        ]);
+        assert_eq!(
+            unit.normalized(),
+            Unit(vec![
+                atom("m", dec!(3)),
+                atom("A", dec!(-2)),
+                atom("s", dec!(-2))
+            ])
+        );
+
+        let cancelled = Unit(vec![atom("m", dec!(1)), atom("m", dec!(-1))]);
+        assert_eq!(cancelled.normalized(), Unit::none());
+
+        // a negative power flips which atoms come first
+        let flipped = Unit(vec![atom("m", dec!(1)), atom("s", dec!(-1))]).pow(dec!(-1));
+        assert_eq!(flipped, Unit(vec![atom("s", dec!(1)), atom("m", dec!(-1))]));
+    }
+
+    #[test]
+    fn unit_reciprocal() {
+        let m = Unit::from(UnitAtom::base("m"));
+        let s = Unit::from(UnitAtom::base("s"));
+
+        assert_eq!(
+            m.reciprocal(),
+            Unit(vec![UnitAtom {
+                name: "m",
+                exp: dec!(-1),
+                span: None,
+            }])
+        );
+        assert_eq!(m.clone() * m.reciprocal(), Unit::none());
+
+        let speed = m.clone() / s.clone();
+        assert_eq!(speed.reciprocal(), s.clone() / m.clone());
+        assert_eq!(speed.clone() * speed.reciprocal(), Unit::none());
+
+        let force = Unit::from(UnitAtom::base("kg")) * m / s.pow(dec!(2));
+        assert!((force.clone() * force.reciprocal()).is_empty());
+        assert_eq!(Unit::none().reciprocal(), Unit::none());
+    }
+
+    #[test]
+    fn unit_atoms() {
+        let unit = Unit::none()
+            * Unit::from(UnitAtom::base("m")).pow(dec!(2))
+            * Unit::from(UnitAtom::base("kg"))
+            * Unit::from(UnitAtom::base("s")).pow(dec!(-2));
+
+        let positive: Decimal = unit
+            .atoms()
+            .map(|u| u.exp())
+            .filter(|e| e.is_sign_positive())
+            .sum();
+        assert_eq!(positive, dec!(3));
+        assert_eq!(unit.len(), 3);
+        assert!(!unit.is_empty());
+        assert!(Unit::none().is_empty());
+        assert_eq!(Unit::none().atoms().count(), 0);
+    }
+
+    #[test]
+    fn unit_pow() {
+        let m = || Unit::from(UnitAtom::base("m"));
+        let s = || Unit::from(UnitAtom::base("s"));
+
+        assert_eq!(m().pow(dec!(2)), m() * m());
+        assert_eq!(
+            (m() / s()).pow(dec!(3)),
+            m() * m() * m() / (s() * s() * s())
+        );
+        assert_eq!(Unit::none().pow(dec!(5)), Unit::none());
+        assert_eq!(m().pow(dec!(0)), Unit::none());
+        assert_eq!(m().pow(dec!(0.5)).0[0].exp, dec!(0.5));
+    }
+
+    #[test]
+    fn quantity_pow() {
+        let m = |v: Decimal| Quantity::new(v, UnitAtom::base("m").into(), 0..0);
+        let num = |v: Decimal| Quantity::num(v, 0..0);
+
+        assert_eq!(num(dec!(2)).pow(num(dec!(10))).unwrap(), num(dec!(1024)));
+        assert_eq!(num(dec!(2)).pow(num(dec!(-1))).unwrap(), num(dec!(0.5)));
+
+        let m2 = Quantity::new(dec!(9), Unit::from(UnitAtom::base("m")).pow(dec!(2)), 0..0);
+        assert_eq!(m(dec!(3)).pow(num(dec!(2))).unwrap(), m2);
+
+        let root = m2.pow(num(dec!(0.5))).unwrap();
+        assert_eq!(root.unit, Unit::from(UnitAtom::base("m")));
+        assert!((root.value - dec!(3)).abs() < dec!(0.0000001));
+
+        let err = m(dec!(2)).pow(Quantity::base("s", 0..0)).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+        assert_eq!(
+            num(dec!(0)).pow(num(dec!(-1))).unwrap_err().typ,
+            ErrorType::DomainError
+        );
+    }
+
+    #[test]
+    fn quantity_neg() {
+        let m = Quantity::new(dec!(5), UnitAtom::base("m").into(), 0..0);
+
+        assert_eq!(
+            -m.clone(),
+            Quantity::new(dec!(-5), UnitAtom::base("m").into(), 0..0)
+        );
+        assert_eq!(-(-m.clone()), m);
+        assert_eq!(-Quantity::num(dec!(3), 0..0), Quantity::num(dec!(-3), 0..0));
+    }
+
+    #[test]
+    fn quantity_op_err() {
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+
+        let err = (q(dec!(1), "m") + q(dec!(2), "s")).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+        assert_eq!(
+            err.to_string(),
+            "non-conformable units for '+': ([m] + [s])"
+        );
+
+        let err = (q(dec!(1), "m") - q(dec!(2), "s")).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+        assert_eq!(
+            err.to_string(),
+            "non-conformable units for '-': ([m] - [s])"
+        );
+
+        let err = (q(dec!(1), "m") / Quantity::num(dec!(0), 0..0)).unwrap_err();
+        assert_eq!(err.typ, ErrorType::ZeroDivision);
+
+        assert_eq!(
+            (q(dec!(5), "m") - q(dec!(2), "m")).unwrap(),
+            q(dec!(3), "m")
+        );
+        assert!((q(dec!(1), "m") * q(dec!(2), "s")).is_ok());
+    }
+
+    #[test]
+    fn quantity_sqrt() {
+        let m = |exp| UnitAtom {
+            name: "m",
+            exp,
+            span: None,
+        };
+
+        let res = Quantity::new(dec!(4), Unit(vec![m(dec!(2))]), 0..0).sqrt();
+        assert_eq!(
+            res.unwrap(),
+            Quantity::new(dec!(2), Unit(vec![m(dec!(1))]), 0..0)
+        );
+
+        let res = Quantity::new(dec!(2), Unit(vec![m(dec!(1))]), 0..0)
+            .sqrt()
+            .unwrap();
+        assert_eq!(res.value.round_dp(6), dec!(1.414214));
+        assert_eq!(res.unit, Unit(vec![m(dec!(0.5))]));
+
+        let err = Quantity::num(dec!(-4), 0..0).sqrt().unwrap_err();
+        assert_eq!(err.typ, ErrorType::DomainError);
+    }
+
+    #[test]
+    fn derived_units() {
+        let atom = |name, exp: i32| {
+            Unit::from(UnitAtom {
+                name,
+                exp: exp.into(),
+                span: None,
+            })
+        };
+
+        let mut table = DerivedUnits::new();
+        table.register("N", atom("kg", 1) * atom("m", 1) * atom("s", -2));
+        table.register("J", atom("N", 1) * atom("m", 1));
+
+        let newton = Quantity::new(dec!(1), atom("N", 1), 0..0);
+        let expanded = newton.expand_derived(&table);
+        assert_eq!(expanded.unit, atom("kg", 1) * atom("m", 1) * atom("s", -2));
+        assert_eq!(newton.unit, atom("N", 1));
+
+        let base = Quantity::new(dec!(1), atom("kg", 1) * atom("m", 1) / atom("s", 2), 0..0);
+        assert!((newton.clone() + base.clone()).is_err());
+        assert_eq!((expanded + base).unwrap().value, dec!(2));
+
+        assert_eq!(
+            table.expand(&(atom("J", 1) / atom("s", 1))),
+            atom("kg", 1) * atom("m", 2) * atom("s", -3)
+        );
+
+        let mut cyclic = DerivedUnits::new();
+        cyclic.register("a", atom("b", 1));
+        cyclic.register("b", atom("a", 1));
+        cyclic.expand(&atom("a", 1));
+    }
+
+    #[test]
+    fn map_value() {
+        let m = || Unit::from(UnitAtom::base("m"));
+        let q = Quantity::new(dec!(2.7), m(), 3..8);
+
+        let floored = q.clone().map_value(|v| v.floor());
+        assert_eq!(floored.value, dec!(2));
+        assert_eq!(floored.unit, m());
+        assert_eq!(floored.span, 3..8);
+        assert_eq!(q.clone().map_value(|v| -v), -q.clone());
+        assert_eq!((-q).abs().value, dec!(2.7));
+    }
+
+    #[test]
+    fn saturating() {
+        let m = || Unit::from(UnitAtom::base("m"));
+        let q = |value, unit| Quantity::new(value, unit, 0..0);
+
+        let sum = q(Decimal::MAX, m())
+            .saturating_add(q(dec!(1), m()))
+            .unwrap();
+        assert_eq!(sum, q(Decimal::MAX, m()));
+        let sum = q(Decimal::MIN, m())
+            .saturating_add(q(dec!(-1), m()))
+            .unwrap();
+        assert_eq!(sum, q(Decimal::MIN, m()));
+        let sum = q(dec!(1), m()).saturating_add(q(dec!(2), m())).unwrap();
+        assert_eq!(sum, q(dec!(3), m()));
+
+        let s = Unit::from(UnitAtom::base("s"));
+        let err = q(Decimal::MAX, m())
+            .saturating_add(q(dec!(1), s.clone()))
+            .unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+
+        let product = q(Decimal::MAX, m()).saturating_mul(q(dec!(2), s.clone()));
+        assert_eq!(product, q(Decimal::MAX, m() * s.clone()));
+        let product = q(Decimal::MAX, m()).saturating_mul(q(dec!(-2), s.clone()));
+        assert_eq!(product, q(Decimal::MIN, m() * s));
+        assert_eq!(
+            q(dec!(2), m()).saturating_mul(q(dec!(3), m())),
+            q(dec!(6), m().pow(dec!(2)))
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        assert!(Unit::default().is_dimensionless());
+        assert_eq!(Unit::default(), Unit::none());
+
+        let q = Quantity::default();
+        assert!(q.is_zero());
+        assert!(q.is_scalar());
+        assert_eq!(q, Quantity::zero(0..0));
+    }
+
+    #[test]
+    fn base_units() {
+        let atom = |name, exp: i32| {
+            Unit::from(UnitAtom {
+                name,
+                exp: exp.into(),
+                span: None,
+            })
+        };
+        let q = |value, unit| Quantity::new(value, unit, 0..0);
+
+        let mut table = ConversionTable::new();
+        let km = q(dec!(1), atom("km", 1));
+        assert_eq!(km.to_base_units(&table), q(dec!(1000), atom("m", 1)));
+        assert!(km.equals_dimensional(&q(dec!(1000), atom("m", 1)), &table));
+        assert!(!km.equals_dimensional(&q(dec!(1), atom("m", 1)), &table));
+        assert!(!km.equals_dimensional(&q(dec!(1000), atom("s", 1)), &table));
+
+        // mass stays in kg instead of becoming 1000 g
+        let kg = q(dec!(2), atom("kg", 1));
+        assert_eq!(kg.to_base_units(&table), kg);
+        let grams = q(dec!(500), atom("g", 1) / atom("s", 2));
+        assert_eq!(
+            grams.to_base_units(&table),
+            q(dec!(0.5), atom("kg", 1) / atom("s", 2))
+        );
+        assert!(q(dec!(2000), atom("g", 1)).equals_dimensional(&kg, &table));
+
+        let speed = q(dec!(3), atom("km", 1) / atom("ms", 1));
+        assert_eq!(
+            speed.to_base_units(&table),
+            q(dec!(3000000), atom("m", 1) / atom("s", 1))
+        );
+
+        table.register_derived("N", atom("kg", 1) * atom("m", 1) * atom("s", -2));
+        let newton = q(dec!(2), atom("N", 1));
+        let base = q(dec!(2), atom("kg", 1) * atom("m", 1) * atom("s", -2));
+        assert_eq!(newton.to_base_units(&table), base);
+        assert!(newton.equals_dimensional(&base, &table));
+        assert!(q(dec!(2000), atom("mN", 1)).equals_dimensional(&base, &table));
+        assert!(!newton.equals_dimensional(&q(dec!(2), atom("kg", 1) * atom("m", 1)), &table));
+
+        // the conversion itself works through the expansion too
+        let converted = newton.convert_to(&base.unit, &table).unwrap();
+        assert_eq!(converted, base);
+    }
+
+    #[test]
+    fn quantity_rem() {
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+
+        assert_eq!(
+            (Quantity::num(dec!(7), 0..0) % Quantity::num(dec!(3), 0..0)).unwrap(),
+            Quantity::num(dec!(1), 0..0)
+        );
+        assert_eq!(
+            (q(dec!(7), "m") % q(dec!(3), "m")).unwrap(),
+            q(dec!(1), "m")
+        );
+        assert_eq!(
+            (q(dec!(7), "m") % q(dec!(3), "s")).unwrap_err().typ,
+            ErrorType::TypeError
+        );
+        assert_eq!(
+            (q(dec!(7), "m") % q(dec!(0), "m")).unwrap_err().typ,
+            ErrorType::ZeroDivision
+        );
+    }
+
+    #[test]
+    fn rounding() {
+        let m = |v: Decimal| Quantity::new(v, UnitAtom::base("m").into(), 0..0);
+
+        let third = (m(dec!(10)) / Quantity::num(dec!(3), 0..0)).unwrap();
+        assert_eq!(third.round_dp(2).to_string(), "3.33 [m]");
+        assert_eq!(third.value, dec!(10) / dec!(3));
+
+        assert_eq!(
+            Quantity::num(dec!(12345), 0..0).to_sig_figs(2).to_string(),
+            "12000"
+        );
+        assert_eq!(m(dec!(0.0012345)).to_sig_figs(3).to_string(), "0.00123 [m]");
+        assert_eq!(m(dec!(0)).to_sig_figs(3).to_string(), "0 [m]");
+    }
+
+    #[test]
+    fn unit_round_trip() {
+        let m = || Unit::from(UnitAtom::base("m"));
+        let s = || Unit::from(UnitAtom::base("s"));
+        let kg = || Unit::from(UnitAtom::base("kg"));
+        let amp = || Unit::from(UnitAtom::base("A"));
+
+        let units = [
+            Unit::none(),
+            m(),
+            m() / s(),
+            kg() * m() / s().pow(dec!(2)),
+            s().reciprocal(),
+            m().pow(dec!(0.5)),
+            kg() / (s() * amp()),
+            (kg() * m()).pow(dec!(2)) / (s().pow(dec!(3)) * amp()),
+        ];
+
+        for unit in units {
+            let shown = unit.to_string();
+            assert_eq!(Unit::parse(&shown).unwrap(), unit, "{}", shown);
+
+            let q = Quantity::new(dec!(2.5), unit, 0..0);
+            let shown = q.to_string();
+            assert_eq!(Quantity::parse(&shown).unwrap(), q, "{}", shown);
+        }
+
+        assert_eq!(Unit::parse("kg/s m").unwrap(), kg() * m() / s());
+        assert_eq!(
+            Unit::parse("1/(s A)").unwrap(),
+            Unit::parse("[1/s/A]").unwrap()
+        );
+        assert_eq!(Unit::parse("[]").unwrap(), Unit::none());
+
+        let err = |src| Unit::parse(src).unwrap_err().span.into_range();
+        assert_eq!(err("[m"), 0..1);
+        assert_eq!(err("(m"), 0..1);
+        assert_eq!(err("[m]]"), 3..4);
+        assert_eq!(err("1 m"), 0..1);
+        assert_eq!(err("m/"), 1..2);
+    }
+
+    #[test]
+    fn quantity_parse() {
+        let atom = |name, exp: i32| {
+            Unit::from(UnitAtom {
+                name,
+                exp: exp.into(),
+                span: None,
+            })
+        };
+
+        assert_eq!(
+            Quantity::parse("42").unwrap(),
+            Quantity::num(dec!(42), 0..0)
+        );
+        assert_eq!(
+            Quantity::parse("5 m").unwrap(),
+            Quantity::new(dec!(5), atom("m", 1), 0..0)
+        );
+        assert_eq!(
+            Quantity::parse("3.2 kg/s").unwrap(),
+            Quantity::new(dec!(3.2), atom("kg", 1) / atom("s", 1), 0..0)
+        );
+        assert_eq!(
+            Quantity::parse("9.81 kg m / s^2").unwrap(),
+            Quantity::new(
+                dec!(9.81),
+                atom("kg", 1) * atom("m", 1) / atom("s", 2),
+                0..0
+            )
+        );
+        assert_eq!(
+            Quantity::parse("2 s^-1").unwrap(),
+            Quantity::new(dec!(2), atom("s", -1), 0..0)
+        );
+        assert_eq!(Quantity::parse("5 m").unwrap().span, 0..3);
+
+        let err = Quantity::parse("5 +").unwrap_err();
+        assert_eq!(err.typ, ErrorType::UndefinedSyntax);
+        assert_eq!(err.span.into_range(), 2..3);
+
+        assert_eq!(Quantity::parse("5 m/").unwrap_err().span.into_range(), 3..4);
+        assert_eq!(Quantity::parse("5 m^").unwrap_err().span.into_range(), 3..4);
+        assert_eq!(
+            Quantity::parse("5 * m").unwrap_err().span.into_range(),
+            2..3
+        );
+        assert_eq!(
+            Quantity::parse("5 $").unwrap_err().typ,
+            ErrorType::CouldNotLex
+        );
+        assert!(Quantity::parse("").is_err());
+    }
+
+    #[test]
+    fn quantity_abs() {
+        let m = |v: Decimal| Quantity::new(v, UnitAtom::base("m").into(), 0..0);
+
+        assert_eq!(m(dec!(-5)).abs(), m(dec!(5)));
+        assert_eq!(m(dec!(5)).abs(), m(dec!(5)));
+        assert_eq!(m(dec!(0)).abs(), m(dec!(0)));
+        assert_eq!(
+            Quantity::num(dec!(-3), 0..0).abs(),
+            Quantity::num(dec!(3), 0..0)
+        );
+        assert_eq!(
+            Quantity::num(dec!(0), 0..0).abs(),
+            Quantity::num(dec!(0), 0..0)
+        );
+    }
+
+    #[test]
+    fn hash() {
+        use std::collections::HashSet;
+
+        let node = |span: Range<usize>| {
+            let num = Node::new(NodeType::Num(dec!(1)), span.clone());
+            let unit = Node::new(NodeType::Unit("m"), span.clone());
+            Node::new(NodeType::Add(num.into(), unit.into()), span)
+        };
+        let nodes: HashSet<_> = [node(0..5), node(3..8)].into_iter().collect();
+        assert_eq!(nodes.len(), 1);
+
+        let q = |v: Decimal, span| Quantity::new(v, UnitAtom::base("m").into(), span);
+        let quantities: HashSet<_> = [q(dec!(1.0), 0..1), q(dec!(1.00), 4..6), q(dec!(2), 0..1)]
+            .into_iter()
+            .collect();
+        assert_eq!(quantities.len(), 2);
+    }
+
+    #[test]
+    fn unit_atom_accessors() {
+        let atom = UnitAtom::base("m");
+        assert_eq!(atom.name(), "m");
+        assert_eq!(atom.exp(), dec!(1));
+    }
+
+    #[test]
+    fn node_literal() {
+        let num = Node::new(NodeType::Num(dec!(4.5)), 0..3);
+        let unit = Node::new(NodeType::Unit("m"), 4..5);
+
+        assert_eq!(Decimal::try_from(&num).unwrap(), dec!(4.5));
+        assert_eq!(<&str>::try_from(&unit).unwrap(), "m");
+
+        let sum = Node::new(NodeType::Add(num.clone().into(), unit.clone().into()), 0..5);
+        let err = Decimal::try_from(&sum).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+        assert_eq!(err.span.into_range(), 0..5);
+        assert_eq!(err.to_string(), "expected a number literal, found: Add");
+
+        let err = <&str>::try_from(&num).unwrap_err();
+        assert_eq!(err.to_string(), "expected a unit, found: Num");
+    }
+
+    #[test]
+    fn scope_indent() {
+        let unit = |name| Node::new(NodeType::Unit(name), 0..0);
+        let scope = |nodes| Node::new(NodeType::Scope(nodes), 0..0);
+
+        assert_eq!(scope(vec![unit("a"), unit("b")]).to_string(), "{\na\nb\n}");
+
+        let nested = scope(vec![
+            unit("a"),
+            scope(vec![unit("b"), scope(vec![unit("c")])]),
+            unit("d"),
+        ]);
+        assert_eq!(nested.to_string(), "{\na\n{\n  b\n  {\n    c\n  }\n}\nd\n}");
+
+        let assign = Node::new(NodeType::Assign("x", scope(vec![unit("a")]).into()), 0..0);
+        assert_eq!(
+            scope(vec![scope(vec![assign])]).to_string(),
+            "{\n{\n  (x = {\n    a\n  })\n}\n}"
+        );
+    }
+
+    #[test]
+    fn number_format() {
+        assert_eq!(format_number(&dec!(5.00)), "5");
+        assert_eq!(format_number(&dec!(5.10)), "5.1");
+        assert_eq!(format_number(&dec!(120)), "120");
+        assert_eq!(format_number(&dec!(0.0)), "0");
+        assert_eq!(format_number(&dec!(-0.0)), "0");
+        assert_eq!(format_number(&dec!(-2.500)), "-2.5");
+
+        let q = Quantity::new(dec!(3.00), UnitAtom::base("m").into(), 0..0);
+        assert_eq!(q.to_string(), "3 [m]");
+        assert_eq!(Quantity::num(dec!(1.50), 0..0).to_string(), "1.5");
+        assert_eq!(Node::new(NodeType::Num(dec!(2.0)), 0..0).to_string(), "2");
+    }
+
+    #[test]
+    fn grouped_format() {
+        let m = |v| Quantity::new(v, UnitAtom::base("m").into(), 0..0);
+        let num = |v| Quantity::num(v, 0..0);
+
+        assert_eq!(m(dec!(1234567)).format_grouped(','), "1,234,567 [m]");
+        assert_eq!(
+            num(dec!(-1234567.891)).format_grouped(','),
+            "-1,234,567.891"
+        );
+        assert_eq!(num(dec!(123456)).format_grouped('_'), "123_456");
+        assert_eq!(num(dec!(-999)).format_grouped(','), "-999");
+        assert_eq!(num(dec!(0.5)).format_grouped(','), "0.5");
+        assert_eq!(num(dec!(1000.00)).format_grouped(' '), "1 000");
+
+        let q = m(dec!(12.5));
+        assert_eq!(q.format_grouped(','), q.to_string());
+    }
+
+    #[test]
+    fn humanize() {
+        let mut table = ConversionTable::new();
+        table.register("km", "m", dec!(1000));
+        table.register("mm", "m", dec!(0.001));
+        table.register("ms", "s", dec!(0.001));
+        table.register("us", "s", dec!(0.000001));
+
+        let q = |v, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+        let human = |v, name| q(v, name).humanize(&table).to_string();
+
+        assert_eq!(human(dec!(1500), "m"), "1.5 [km]");
+        assert_eq!(human(dec!(-1500), "m"), "-1.5 [km]");
+        assert_eq!(human(dec!(0.002), "s"), "2 [ms]");
+        assert_eq!(human(dec!(0.0000035), "s"), "3.5 [us]");
+        assert_eq!(human(dec!(2500), "mm"), "2.5 [m]");
+        assert_eq!(human(dec!(1), "m"), "1 [m]");
+        assert_eq!(human(dec!(1000), "m"), "1 [km]");
+        assert_eq!(human(dec!(999), "m"), "999 [m]");
+        assert_eq!(human(dec!(0), "m"), "0 [m]");
+
+        let speed = Quantity::new(
+            dec!(1500),
+            Unit::from(UnitAtom::base("m")) / UnitAtom::base("s").into(),
+            0..0,
+        );
+        assert_eq!(speed.humanize(&table), speed);
+        assert_eq!(Quantity::num(1500, 0..0).humanize(&table).value, dec!(1500));
+    }
+
+    #[test]
+    fn recip() {
+        let m = |v| Quantity::new(v, UnitAtom::base("m").into(), 0..0);
+
+        let res = m(dec!(2)).recip().unwrap();
+        assert_eq!(res.value, dec!(0.5));
+        assert_eq!(res.unit, Unit::from(UnitAtom::base("m")).reciprocal());
+        assert_eq!(res.to_string(), "0.5 [1/m]");
+
+        let err = Quantity::zero(0..0).recip().unwrap_err();
+        assert_eq!(err.typ, ErrorType::ZeroDivision);
+        assert!(m(dec!(0)).recip().is_err());
+
+        let x = m(dec!(-4));
+        assert_eq!(x.clone().recip().unwrap().recip().unwrap(), x);
+    }
+
+    #[test]
+    fn zero_one() {
+        let zero = Quantity::zero(0..0);
+        assert!(zero.is_scalar());
+        assert!(zero.is_zero());
+        assert_eq!(zero.value, dec!(0));
+        assert_eq!(Quantity::one(0..0), Quantity::num(1, 0..0));
+        assert!(!Quantity::one(0..0).is_zero());
+
+        let m = crate::morph::test_utils::eval("def m; 0 m").unwrap();
+        assert!(m.is_zero());
+        assert!(!m.is_scalar());
+        assert_eq!(m.to_string(), "0 [m]");
+    }
+
+    #[test]
+    fn overflow() {
+        let max = || Quantity::new(Decimal::MAX, UnitAtom::base("m").into(), 0..0);
+        let tiny = Quantity::num(dec!(0.0001), 0..0);
+
+        let err = (max() * max()).unwrap_err();
+        assert_eq!(err.typ, ErrorType::Overflow);
+        assert_eq!(err.to_string(), "arithmetic overflow in '*'");
+        assert_eq!((max() + max()).unwrap_err().typ, ErrorType::Overflow);
+        assert_eq!((-max() - max()).unwrap_err().typ, ErrorType::Overflow);
+        assert_eq!((max() / tiny).unwrap_err().typ, ErrorType::Overflow);
+        assert!((max() - max()).is_ok());
+    }
+
+    #[test]
+    fn quantity_errors() {
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+        let mismatch = || QuantityError::UnitMismatch {
+            lhs: "[m]".to_string(),
+            rhs: "[s]".to_string(),
+        };
+
+        assert_eq!(q(dec!(1), "m").try_add(&q(dec!(2), "s")), Err(mismatch()));
+        assert_eq!(q(dec!(1), "m").try_sub(&q(dec!(2), "s")), Err(mismatch()));
+        assert_eq!(
+            q(Decimal::MAX, "m").try_mul(&q(dec!(2), "s")),
+            Err(QuantityError::Overflow)
+        );
+        assert_eq!(
+            q(dec!(1), "m").try_div(&q(dec!(0), "s")),
+            Err(QuantityError::DivByZero)
+        );
+        assert_eq!(
+            q(dec!(1), "degC").try_add(&q(dec!(1), "degC")),
+            Err(QuantityError::Affine("degC".to_string()))
+        );
+        assert_eq!(mismatch().to_string(), "non-conformable units: [m] and [s]");
+
+        let sum = q(dec!(1), "m").try_add(&q(dec!(2), "m")).unwrap();
+        assert_eq!(sum, q(dec!(3), "m"));
+    }
+
+    #[test]
+    fn temperature() {
+        let table = ConversionTable::temperatures();
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+        let unit = |name| Unit::from(UnitAtom::base(name));
+
+        let convert = |v, from, to| q(v, from).convert_temperature(&unit(to), &table);
+
+        assert_eq!(convert(dec!(0), "degC", "K").unwrap(), q(dec!(273.15), "K"));
+        assert_eq!(
+            convert(dec!(32), "degF", "degC").unwrap(),
+            q(dec!(0), "degC")
+        );
+        assert_eq!(
+            convert(dec!(212), "degF", "degC").unwrap(),
+            q(dec!(100), "degC")
+        );
+        assert_eq!(
+            convert(dec!(-40), "degC", "degF").unwrap(),
+            q(dec!(-40), "degF")
+        );
+        assert_eq!(
+            convert(dec!(0), "K", "degF").unwrap(),
+            q(dec!(-459.67), "degF")
+        );
+        assert_eq!(
+            convert(dec!(1), "m", "K").unwrap_err().typ,
+            ErrorType::TypeError
+        );
+
+        let err = (q(dec!(20), "degC") + q(dec!(5), "degC")).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+        assert_eq!(
+            err.to_string(),
+            "'+' on degC is ambiguous, convert to K first to use temperature deltas"
+        );
+        assert!((q(dec!(20), "degF") - q(dec!(5), "degF")).is_err());
+        assert!((q(dec!(20), "K") + q(dec!(5), "K")).is_ok());
+    }
+
+    #[test]
+    fn quantity_ord() {
+        use std::cmp::Ordering::*;
+
+        let q = |v: Decimal, name| Quantity::new(v, UnitAtom::base(name).into(), 0..0);
+
+        assert_eq!(q(dec!(2), "m").partial_cmp(&q(dec!(3), "m")), Some(Less));
+        assert_eq!(q(dec!(3), "m").partial_cmp(&q(dec!(3), "m")), Some(Equal));
+        assert_eq!(q(dec!(2), "m").partial_cmp(&q(dec!(3), "s")), None);
+        assert!(q(dec!(4), "m") > q(dec!(3), "m"));
+        assert!(!q(dec!(2), "m").lt(&q(dec!(3), "s")));
+        assert!(!q(dec!(2), "m").ge(&q(dec!(3), "s")));
+    }
+
+    #[test]
+    fn convert() {
+        let mut table = ConversionTable::new();
+        table.register("km", "m", dec!(1000));
+        table.register("Mm", "km", dec!(1000));
+        table.register("mm", "m", dec!(0.001));
+
+        let unit = |name| Unit::from(UnitAtom::base(name));
+        let q = |v: Decimal, name| Quantity::new(v, unit(name), 0..0);
+
+        eq(
+            q(dec!(5), "km").convert_to(&unit("m"), &table),
+            q(dec!(5000), "m"),
+        );
+        eq(
+            q(dec!(2), "Mm").convert_to(&unit("m"), &table),
+            q(dec!(2000000), "m"),
+        );
+        eq(
+            q(dec!(1500), "mm").convert_to(&unit("km"), &table),
+            q(dec!(0.0015), "km"),
+        );
+        eq(
+            q(dec!(3), "m").convert_to(&unit("m"), &table),
+            q(dec!(3), "m"),
+        );
+
+        let area = Quantity::new(dec!(1), unit("km").pow(dec!(2)), 0..0);
+        eq(
+            area.convert_to(&unit("m").pow(dec!(2)), &table),
+            Quantity::new(dec!(1000000), unit("m").pow(dec!(2)), 0..0),
+        );
+
+        let err = q(dec!(3), "m").convert_to(&unit("s"), &table).unwrap_err();
+        assert_eq!(err.typ, ErrorType::TypeError);
+
+        fn eq(res: RuntimeResult, expected: Quantity) {
+            assert_eq!(res.unwrap(), expected);
+        }
+    }
+}
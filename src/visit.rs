@@ -0,0 +1,224 @@
+use crate::types::*;
+
+pub trait Visitor<'a> {
+    fn visit_node(&mut self, n: &Node<'a>) {
+        walk_node(self, n)
+    }
+
+    fn visit_num(&mut self, _n: &Node<'a>) {}
+
+    fn visit_unit(&mut self, _n: &Node<'a>) {}
+
+    fn visit_def(&mut self, _n: &Node<'a>) {}
+
+    fn visit_err(&mut self, _n: &Node<'a>) {}
+}
+
+// dispatches leaves to their hook and recurses into every child of an inner node
+pub fn walk_node<'a, V: Visitor<'a> + ?Sized>(v: &mut V, n: &Node<'a>) {
+    use NodeType::*;
+
+    match &n.typ {
+        Def(_) => v.visit_def(n),
+        DefFn { body, .. } => v.visit_node(body),
+        Unit(_) => v.visit_unit(n),
+        Num(_) => v.visit_num(n),
+        ParseError => v.visit_err(n),
+
+        IfElse {
+            cond,
+            if_body,
+            else_body,
+        } => {
+            v.visit_node(cond);
+            v.visit_node(if_body);
+            if let Some(body) = else_body {
+                v.visit_node(body);
+            }
+        }
+
+        Add(lhs, rhs)
+        | Sub(lhs, rhs)
+        | Mul(lhs, rhs)
+        | Div(lhs, rhs)
+        | Mod(lhs, rhs)
+        | Pow(lhs, rhs)
+        | BitAnd(lhs, rhs)
+        | BitOr(lhs, rhs)
+        | Shl(lhs, rhs)
+        | Shr(lhs, rhs)
+        | Equal(lhs, rhs)
+        | NeEqual(lhs, rhs)
+        | GreaterEqual(lhs, rhs)
+        | LesserEqual(lhs, rhs)
+        | Greater(lhs, rhs)
+        | Lesser(lhs, rhs) => {
+            v.visit_node(lhs);
+            v.visit_node(rhs);
+        }
+
+        UnryNot(val) | UnrySub(val) | Percent(val) | Abs(val) => v.visit_node(val),
+        Convert { expr, .. } => v.visit_node(expr),
+
+        Assign(_, val)
+        | AddAssign(_, val)
+        | SubAssign(_, val)
+        | MulAssign(_, val)
+        | DivAssign(_, val)
+        | PowAssign(_, val) => v.visit_node(val),
+
+        FnCall { args: nodes, .. } | Scope(nodes) | List(nodes) => {
+            for n in nodes {
+                v.visit_node(n);
+            }
+        }
+    }
+}
+
+// rebuilds the tree bottom-up, `f` sees every node after its children were
+// mapped and the spans are kept unless `f` changes them
+#[allow(dead_code)]
+pub fn map_nodes<'a, F>(node: Node<'a>, f: &mut F) -> Node<'a>
+where
+    F: FnMut(Node<'a>) -> Node<'a>,
+{
+    use NodeType::*;
+
+    let mut map = |n: Box<Node<'a>>| Box::new(map_nodes(*n, f));
+
+    let typ = match node.typ {
+        typ @ (Def(_) | Unit(_) | Num(_) | ParseError) => typ,
+        DefFn { name, params, body } => DefFn {
+            name,
+            params,
+            body: map(body),
+        },
+        IfElse {
+            cond,
+            if_body,
+            else_body,
+        } => IfElse {
+            cond: map(cond),
+            if_body: map(if_body),
+            else_body: else_body.map(&mut map),
+        },
+
+        Add(lhs, rhs) => Add(map(lhs), map(rhs)),
+        Sub(lhs, rhs) => Sub(map(lhs), map(rhs)),
+        Mul(lhs, rhs) => Mul(map(lhs), map(rhs)),
+        Div(lhs, rhs) => Div(map(lhs), map(rhs)),
+        Mod(lhs, rhs) => Mod(map(lhs), map(rhs)),
+        Pow(lhs, rhs) => Pow(map(lhs), map(rhs)),
+        BitAnd(lhs, rhs) => BitAnd(map(lhs), map(rhs)),
+        BitOr(lhs, rhs) => BitOr(map(lhs), map(rhs)),
+        Shl(lhs, rhs) => Shl(map(lhs), map(rhs)),
+        Shr(lhs, rhs) => Shr(map(lhs), map(rhs)),
+        Equal(lhs, rhs) => Equal(map(lhs), map(rhs)),
+        NeEqual(lhs, rhs) => NeEqual(map(lhs), map(rhs)),
+        GreaterEqual(lhs, rhs) => GreaterEqual(map(lhs), map(rhs)),
+        LesserEqual(lhs, rhs) => LesserEqual(map(lhs), map(rhs)),
+        Greater(lhs, rhs) => Greater(map(lhs), map(rhs)),
+        Lesser(lhs, rhs) => Lesser(map(lhs), map(rhs)),
+
+        UnryNot(val) => UnryNot(map(val)),
+        UnrySub(val) => UnrySub(map(val)),
+        Percent(val) => Percent(map(val)),
+        Abs(val) => Abs(map(val)),
+        Convert { expr, target } => Convert {
+            expr: map(expr),
+            target,
+        },
+
+        Assign(name, val) => Assign(name, map(val)),
+        AddAssign(name, val) => AddAssign(name, map(val)),
+        SubAssign(name, val) => SubAssign(name, map(val)),
+        MulAssign(name, val) => MulAssign(name, map(val)),
+        DivAssign(name, val) => DivAssign(name, map(val)),
+        PowAssign(name, val) => PowAssign(name, map(val)),
+
+        FnCall { name, args } => FnCall {
+            name,
+            args: args.into_iter().map(|n| map_nodes(n, f)).collect(),
+        },
+        Scope(nodes) => Scope(nodes.into_iter().map(|n| map_nodes(n, f)).collect()),
+        List(items) => List(items.into_iter().map(|n| map_nodes(n, f)).collect()),
+    };
+
+    f(Node::new(typ, node.span))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::morph::test_utils::*;
+
+    #[derive(Default)]
+    struct NumCounter(usize);
+
+    impl<'a> Visitor<'a> for NumCounter {
+        fn visit_num(&mut self, _n: &Node<'a>) {
+            self.0 += 1;
+        }
+    }
+
+    #[derive(Default)]
+    struct UnitCollector<'a>(Vec<&'a str>);
+
+    impl<'a> Visitor<'a> for UnitCollector<'a> {
+        fn visit_unit(&mut self, n: &Node<'a>) {
+            if let NodeType::Unit(name) = n.typ {
+                self.0.push(name);
+            }
+        }
+
+        fn visit_def(&mut self, n: &Node<'a>) {
+            if let NodeType::Def(name) = n.typ {
+                self.0.push(name);
+            }
+        }
+    }
+
+    #[test]
+    fn rewrite() {
+        use crate::morph::parse_program;
+        use rust_decimal_macros::dec;
+
+        let stmt = |src| parse_program(src).0.remove(0);
+
+        let mut zero_to_one = |n: Node<'static>| match n.typ {
+            NodeType::Num(x) if x.is_zero() => Node::new(NodeType::Num(dec!(1)), n.span),
+            _ => n,
+        };
+
+        let res = map_nodes(stmt("2 + 0"), &mut zero_to_one);
+        eq!(res.to_string(), "(2 + 1)");
+        eq!(res.span, 0..5);
+        let NodeType::Add(_, rhs) = &res.typ else {
+            panic!("expected an addition, found: {:?}", res.typ)
+        };
+        eq!(rhs.span, 4..5);
+
+        // x - 0 => x, applied bottom-up so nested matches collapse too
+        let mut drop_sub_zero = |n: Node<'static>| match n.typ {
+            NodeType::Sub(lhs, rhs) if rhs.typ == NodeType::Num(dec!(0)) => *lhs,
+            typ => Node::new(typ, n.span),
+        };
+
+        let res = map_nodes(stmt("f(x - 0 - 0) * 2"), &mut drop_sub_zero);
+        eq!(res, call("f", vec![u("x")]) * n(2));
+    }
+
+    #[test]
+    fn count_nums() {
+        let mut counter = NumCounter::default();
+        counter.visit_node(&parse("1 + 2 * x; y = -3^4; f(5, 6%)").0.unwrap());
+        eq!(counter.0, 6);
+    }
+
+    #[test]
+    fn collect_units() {
+        let mut collector = UnitCollector::default();
+        collector.visit_node(&parse("def m; x = 2 m; { x += 1 s } ; x == m").0.unwrap());
+        eq!(collector.0, vec!["m", "m", "s", "x", "m"]);
+    }
+}